@@ -0,0 +1,531 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::registries::{config_paths, Location};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{anyhow, Context, Result};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile::Item;
+use ureq::{Agent, AgentBuilder, Proxy, Response};
+use url::Url;
+
+/// A certificate verifier that accepts any certificate
+///
+/// This is only used for registries configured as insecure.
+struct Insecure;
+
+impl ServerCertVerifier for Insecure {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The certificates for a registry from its `certs.d/<host[:port]>/` directories
+///
+/// As with Docker and the containers tools, each `*.crt` file holds extra CA
+/// certificates and each `*.cert` file holds a client certificate chain whose
+/// key is in the `*.key` file of the same name.
+#[derive(Default)]
+struct Certs {
+    roots: Vec<Certificate>,
+    client: Option<(Vec<Certificate>, PrivateKey)>,
+}
+
+impl Certs {
+    /// The `certs.d` directories to search, in order of preference
+    fn dirs() -> Vec<PathBuf> {
+        let mut dirs = Vec::new();
+
+        if let Some(path) = std::env::var_os("WYRCAN_CERTS_DIR") {
+            dirs.push(path.into());
+        }
+
+        dirs.extend(config_paths("certs.d"));
+        dirs.push("/etc/docker/certs.d".into());
+        dirs
+    }
+
+    fn read(path: &Path) -> Result<Vec<Item>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::read_all(&mut reader).with_context(|| format!("{:?}", path))
+    }
+
+    /// Loads the certificates from `dir`, if it exists
+    fn load(&mut self, dir: &Path) -> Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("{:?}", dir)),
+        };
+
+        let mut paths = entries
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+
+        for path in paths {
+            match path.extension().and_then(|e| e.to_str()) {
+                Some("crt") => {
+                    for item in Self::read(&path)? {
+                        if let Item::X509Certificate(der) = item {
+                            self.roots.push(Certificate(der));
+                        }
+                    }
+                }
+
+                // The first client certificate found wins.
+                Some("cert") if self.client.is_none() => {
+                    let chain: Vec<_> = Self::read(&path)?
+                        .into_iter()
+                        .filter_map(|item| match item {
+                            Item::X509Certificate(der) => Some(Certificate(der)),
+                            _ => None,
+                        })
+                        .collect();
+
+                    let key = path.with_extension("key");
+                    let key = Self::read(&key)?
+                        .into_iter()
+                        .find_map(|item| match item {
+                            Item::RSAKey(der) | Item::PKCS8Key(der) => Some(PrivateKey(der)),
+                            _ => None,
+                        })
+                        .ok_or_else(|| anyhow!("no private key in {:?}", key))?;
+
+                    self.client = Some((chain, key));
+                }
+
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the certificates for the registry at `host`
+    fn find(host: &str) -> Result<Self> {
+        let mut certs = Self::default();
+
+        for dir in Self::dirs() {
+            certs.load(&dir.join(host))?;
+        }
+
+        Ok(certs)
+    }
+}
+
+/// The value of the first of `vars` that is set and not empty
+fn env(vars: &[&str]) -> Option<String> {
+    vars.iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+}
+
+/// Strips the port, if any, from a `host[:port]`
+fn hostname(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split_once(']').map(|(ip, ..)| ip).unwrap_or(rest);
+    }
+
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Whether `host` is excluded from proxying by a `NO_PROXY` list
+///
+/// Each entry is a host name, which also matches its subdomains, or `*` to
+/// match every host. Leading dots and ports in the entries are ignored.
+fn bypass(no_proxy: &str, host: &str) -> bool {
+    let host = hostname(host).to_ascii_lowercase();
+
+    no_proxy
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .any(|entry| {
+            let entry = hostname(entry.trim_start_matches('*').trim_start_matches('.'));
+            let entry = entry.to_ascii_lowercase();
+
+            entry.is_empty()
+                || host == entry
+                || host
+                    .strip_suffix(&entry)
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+}
+
+/// The proxy to use for `scheme` requests to `host`, from the environment
+fn proxy(scheme: &str, host: &str) -> Result<Option<Proxy>> {
+    let url = match scheme {
+        "https" => env(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]),
+        _ => env(&["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]),
+    };
+
+    let url = match url {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    if env(&["NO_PROXY", "no_proxy"]).is_some_and(|no_proxy| bypass(&no_proxy, host)) {
+        return Ok(None);
+    }
+
+    let proxy = Proxy::new(&url).with_context(|| format!("invalid proxy: {}", url))?;
+    Ok(Some(proxy))
+}
+
+/// A timeout in seconds from the environment variable `var`
+fn timeout(var: &str, default: u64) -> Result<Duration> {
+    let secs = match env(&[var]) {
+        Some(secs) => secs
+            .parse()
+            .with_context(|| format!("{}: invalid timeout: {:?}", var, secs))?,
+        None => default,
+    };
+
+    Ok(Duration::from_secs(secs))
+}
+
+/// The most redirects that we follow for a single request
+const MAX_REDIRECTS: usize = 10;
+
+/// Sends a `method` request for `url` with `headers`, following any redirects
+///
+/// Our agents don't follow redirects themselves, since ureq would send all
+/// the headers on to the new location. Here, `Authorization` is dropped once
+/// a redirect leaves the original host, so that blob redirects to a CDN or to
+/// object storage don't see the user's credentials. Those hosts are talked to
+/// with their own agent, too, rather than with the one for `url`.
+///
+/// The error is boxed, since a `ureq::Error` may carry a whole response.
+pub fn request(
+    agent: &Agent,
+    method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+) -> Result<Response, Box<ureq::Error>> {
+    send(agent, method, url, headers, None)
+}
+
+/// Sends `form` in a POST request for `url`, following any redirects
+///
+/// This works like [`request`], except that a `303 See Other`, or a `301` or
+/// `302`, turns the request into a GET and drops the form, as browsers do.
+pub fn post_form(
+    agent: &Agent,
+    url: &str,
+    form: &[(&str, &str)],
+) -> Result<Response, Box<ureq::Error>> {
+    send(agent, "POST", url, &[], Some(form))
+}
+
+fn send(
+    agent: &Agent,
+    mut method: &str,
+    url: &str,
+    headers: &[(&str, &str)],
+    mut form: Option<&[(&str, &str)]>,
+) -> Result<Response, Box<ureq::Error>> {
+    let host = |url: &Url| {
+        (
+            url.host_str().map(String::from),
+            url.port_or_known_default(),
+        )
+    };
+    let mut url = Url::parse(url).map_err(ureq::Error::from)?;
+    let origin = host(&url);
+
+    let mut redirects = 0;
+
+    loop {
+        let same = host(&url) == origin;
+        let agent = match same {
+            true => agent.clone(),
+            false => foreign(&url).map_err(|e| {
+                let msg = format!("{:#}", e);
+                Box::new(std::io::Error::other(msg).into())
+            })?,
+        };
+
+        let mut req = agent.request_url(method, &url);
+        for (k, v) in headers {
+            if same || !k.eq_ignore_ascii_case("Authorization") {
+                req = req.set(k, v);
+            }
+        }
+
+        let rep = match form {
+            Some(form) => req.send_form(form)?,
+            None => req.call()?,
+        };
+
+        let location = match (rep.status(), rep.header("Location")) {
+            (301 | 302 | 303 | 307 | 308, Some(location)) => location.to_string(),
+            _ => return Ok(rep),
+        };
+
+        // Give up, reporting the last redirect as the failure.
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(Box::new(rep.into()));
+        }
+
+        if rep.status() == 303 || (method == "POST" && rep.status() < 303) {
+            method = "GET";
+            form = None;
+        }
+
+        url = url.join(&location).map_err(ureq::Error::from)?;
+    }
+}
+
+/// The `host[:port]` of `url`, as used to pick its agent
+pub fn host(url: &Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Returns the HTTP agent for a host outside of the registry configuration
+///
+/// This is used for the hosts that a registry sends us on to, such as its
+/// token service or the CDN that serves its blobs.
+pub fn foreign(url: &Url) -> Result<Agent> {
+    let host = host(url);
+    let location = Location {
+        plain_http: url.scheme() == "http",
+        ..Location::from(host.clone())
+    };
+
+    shared(&location, &host)
+}
+
+/// Returns the HTTP agent for talking to `location` at `host`
+///
+/// Agents are built on first use and then shared, so that everything talking
+/// to a host draws on the same pool of connections.
+pub fn shared(location: &Location, host: &str) -> Result<Agent> {
+    type Agents = HashMap<(String, bool, &'static str), Agent>;
+    static AGENTS: OnceLock<Mutex<Agents>> = OnceLock::new();
+
+    // An agent only has one proxy, so pick it for the preferred scheme.
+    let scheme = location.schemes()[0];
+    let key = (host.to_string(), location.verify(), scheme);
+
+    let mut agents = AGENTS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+
+    if let Some(agent) = agents.get(&key) {
+        return Ok(agent.clone());
+    }
+
+    let agent = build(location, host, scheme)?;
+    Ok(agents.entry(key).or_insert(agent).clone())
+}
+
+/// Builds the HTTP agent for talking to `location` at `host`
+///
+/// Proxies are taken from `HTTPS_PROXY`, `HTTP_PROXY` and `NO_PROXY`, and the
+/// timeouts (in seconds) from `WYRCAN_CONNECT_TIMEOUT` and
+/// `WYRCAN_READ_TIMEOUT`.
+fn build(location: &Location, host: &str, scheme: &str) -> Result<Agent> {
+    let certs = Certs::find(host)?;
+
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+
+    for root in certs.roots.iter() {
+        roots
+            .add(root)
+            .with_context(|| format!("{}: invalid CA certificate", host))?;
+    }
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots);
+
+    let mut config = match certs.client {
+        Some((chain, key)) => builder
+            .with_single_cert(chain, key)
+            .with_context(|| format!("{}: invalid client certificate", host))?,
+        None => builder.with_no_client_auth(),
+    };
+
+    if !location.verify() {
+        config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(Insecure));
+    }
+
+    let mut builder = AgentBuilder::new()
+        .tls_config(Arc::new(config))
+        .user_agent(concat!("wyrcan/", env!("CARGO_PKG_VERSION")))
+        .redirects(0)
+        .timeout_connect(timeout("WYRCAN_CONNECT_TIMEOUT", 30)?)
+        .timeout_read(timeout("WYRCAN_READ_TIMEOUT", 60)?);
+
+    if let Some(proxy) = proxy(scheme, host)? {
+        builder = builder.proxy(proxy);
+    }
+
+    Ok(builder.build())
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{bypass, post_form};
+    use crate::api::registries::Location;
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::thread::JoinHandle;
+
+    /// A local HTTP server that answers each connection with a canned reply
+    pub struct Server(TcpListener);
+
+    impl Server {
+        pub fn new() -> Self {
+            Self(TcpListener::bind("127.0.0.1:0").unwrap())
+        }
+
+        /// The `host:port` that the server listens on
+        pub fn host(&self) -> String {
+            format!("localhost:{}", self.0.local_addr().unwrap().port())
+        }
+
+        /// Answers one request on each connection with each of `replies`
+        ///
+        /// Returns the requests that were sent: their head and any body. TLS
+        /// connections are closed at once and recorded as `TLS`, and those
+        /// closed without a request are ignored.
+        pub fn serve(self, replies: Vec<Vec<u8>>) -> JoinHandle<Vec<String>> {
+            std::thread::spawn(move || {
+                let mut requests = Vec::new();
+                let mut replies = replies.into_iter().peekable();
+
+                while replies.peek().is_some() {
+                    let (stream, ..) = self.0.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    match reader.fill_buf().unwrap().first() {
+                        None => continue,
+                        Some(0x16) => {
+                            requests.push("TLS".into());
+                            continue;
+                        }
+                        Some(..) => (),
+                    }
+
+                    // Read up to the blank line that ends the head, then the body.
+                    let mut request = String::new();
+                    while reader.read_line(&mut request).unwrap() > 2 {}
+                    let len = request
+                        .lines()
+                        .filter_map(|l| l.split_once(':'))
+                        .find(|(k, ..)| k.eq_ignore_ascii_case("content-length"))
+                        .map_or(0, |(.., v)| v.trim().parse().unwrap());
+                    let mut body = vec![0; len];
+                    reader.read_exact(&mut body).unwrap();
+                    request.push_str(&String::from_utf8_lossy(&body));
+
+                    reader
+                        .into_inner()
+                        .write_all(&replies.next().unwrap())
+                        .unwrap();
+                    requests.push(request);
+                }
+
+                requests
+            })
+        }
+    }
+
+    /// A reply with `status` (e.g. `200 OK`), `headers` and `body`
+    pub fn reply(status: &str, headers: &[(&str, &str)], body: &[u8]) -> Vec<u8> {
+        let mut reply = format!("HTTP/1.1 {}\r\n", status);
+        for (k, v) in headers {
+            reply.push_str(&format!("{}: {}\r\n", k, v));
+        }
+
+        reply.push_str(&format!(
+            "Content-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        ));
+
+        let mut reply = reply.into_bytes();
+        reply.extend(body);
+        reply
+    }
+
+    #[test]
+    fn no_proxy() {
+        let no_proxy = "localhost, .internal,example.com:443,[::1]";
+
+        assert!(bypass(no_proxy, "localhost:5000"));
+        assert!(bypass(no_proxy, "registry.internal"));
+        assert!(bypass(no_proxy, "example.com"));
+        assert!(bypass(no_proxy, "quay.example.com"));
+        assert!(bypass(no_proxy, "[::1]:5000"));
+        assert!(!bypass(no_proxy, "notexample.com"));
+        assert!(!bypass(no_proxy, "internal.io"));
+        assert!(!bypass(no_proxy, "quay.io"));
+        assert!(bypass("*", "quay.io"));
+        assert!(!bypass("", "quay.io"));
+    }
+
+    #[test]
+    fn redirected_form() {
+        let server = Server::new();
+        let url = format!("http://{}/token", server.host());
+        let location = Location {
+            plain_http: true,
+            ..Location::from(server.host())
+        };
+        let agent = super::shared(&location, &server.host()).unwrap();
+
+        // A 307 repeats the POST with its form, but a 303 turns it into a GET.
+        let server = server.serve(vec![
+            reply("307 Temporary Redirect", &[("Location", "/again")], b""),
+            reply("303 See Other", &[("Location", "/done")], b""),
+            reply("200 OK", &[], b"{}"),
+        ]);
+
+        let form = [("grant_type", "refresh_token"), ("refresh_token", "secret")];
+        post_form(&agent, &url, &form).unwrap();
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /token "));
+        assert!(requests[0].ends_with("grant_type=refresh_token&refresh_token=secret"));
+        assert!(requests[1].starts_with("POST /again "));
+        assert!(requests[1].ends_with("grant_type=refresh_token&refresh_token=secret"));
+        assert!(requests[2].starts_with("GET /done "));
+        assert!(requests[2].ends_with("\r\n\r\n"));
+    }
+}
@@ -0,0 +1,311 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+/// An authentication challenge from a `Www-Authenticate` header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Challenge {
+    /// Send the credentials themselves using the Basic scheme
+    Basic,
+
+    /// Fetch a token from the `realm` and send it using the Bearer scheme
+    Bearer {
+        realm: String,
+        service: Option<String>,
+        scope: Option<String>,
+    },
+}
+
+impl Challenge {
+    /// Parses all challenges in a `Www-Authenticate` header
+    ///
+    /// The header contains one or more challenges in the form `SCHEME` or
+    /// `SCHEME key=value, key="quoted value", ...`, separated by commas.
+    /// Challenges using unknown schemes are skipped.
+    pub fn parse(header: &str) -> Result<Vec<Self>> {
+        let err = || anyhow!("malformed Www-Authenticate header: {:?}", header);
+
+        let mut challenges = Vec::new();
+        let mut input = header.trim_start();
+        while !input.is_empty() {
+            // Parse the scheme.
+            let end = input.find(|c: char| c.is_whitespace() || c == ',');
+            let (scheme, rest) = input.split_at(end.unwrap_or(input.len()));
+            input = rest.trim_start();
+
+            if scheme.is_empty() || scheme.contains('=') {
+                return Err(err());
+            }
+
+            // Parse the parameters up to the next scheme.
+            let mut params = HashMap::new();
+            loop {
+                input = input.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+
+                let eq = match input.find(|c: char| c == '=' || c == ',' || c.is_whitespace()) {
+                    Some(n) if input[n..].starts_with('=') => n,
+                    _ => break,
+                };
+
+                let key = input[..eq].to_ascii_lowercase();
+                input = &input[eq + 1..];
+
+                let value = if let Some(quoted) = input.strip_prefix('"') {
+                    let mut value = String::new();
+                    let mut chars = quoted.char_indices();
+                    loop {
+                        match chars.next() {
+                            Some((.., '\\')) => value.push(chars.next().ok_or_else(err)?.1),
+                            Some((n, '"')) => {
+                                input = &quoted[n + 1..];
+                                break;
+                            }
+                            Some((.., c)) => value.push(c),
+                            None => return Err(err()),
+                        }
+                    }
+                    value
+                } else {
+                    let end = input.find(|c: char| c == ',' || c.is_whitespace());
+                    let (value, rest) = input.split_at(end.unwrap_or(input.len()));
+                    input = rest;
+                    value.into()
+                };
+
+                if key.is_empty() || !(input.is_empty() || input.starts_with([',', ' ', '\t'])) {
+                    return Err(err());
+                }
+
+                params.insert(key, value);
+            }
+
+            if scheme.eq_ignore_ascii_case("basic") {
+                challenges.push(Self::Basic);
+            } else if scheme.eq_ignore_ascii_case("bearer") {
+                challenges.push(Self::Bearer {
+                    realm: params.remove("realm").ok_or_else(err)?,
+                    service: params.remove("service"),
+                    scope: params.remove("scope"),
+                });
+            }
+        }
+
+        Ok(challenges)
+    }
+}
+
+/// A reply from a token service
+///
+/// Registries using the Docker token protocol reply with `token`, while
+/// OAuth2 token endpoints reply with `access_token`. Many send both.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Token {
+    #[serde(default)]
+    token: Option<String>,
+
+    #[serde(default)]
+    access_token: Option<String>,
+
+    #[serde(default)]
+    expires_in: Option<u64>,
+
+    #[serde(default)]
+    refresh_token: Option<String>,
+}
+
+impl Token {
+    /// The lifetime assumed when the token service doesn't specify one
+    const DEFAULT_LIFETIME: Duration = Duration::from_secs(60);
+
+    /// The value of an `Authorization` header using this token
+    pub fn bearer(&self) -> Result<String> {
+        match self.token.as_deref().or(self.access_token.as_deref()) {
+            Some(token) if !token.is_empty() => Ok(format!("Bearer {}", token)),
+            _ => Err(anyhow!("token service replied without a token")),
+        }
+    }
+
+    /// How long the token remains valid after it was issued
+    pub fn lifetime(&self) -> Duration {
+        self.expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(Self::DEFAULT_LIFETIME)
+    }
+
+    /// The new refresh token, if the token service issued one
+    pub fn refresh_token(&self) -> Option<&str> {
+        self.refresh_token.as_deref()
+    }
+}
+
+/// The key for a cached token: the registry, the realm and the scope
+type Key = (String, String, String);
+
+#[derive(Debug, Default)]
+struct Entries {
+    challenges: HashMap<String, Challenge>,
+    tokens: HashMap<Key, (String, Instant)>,
+    refresh: HashMap<String, String>,
+}
+
+/// A cache of authorizations shared by all requests to all registries
+///
+/// We remember the last challenge from each registry so that later requests
+/// can be authorized up front, without first being rejected. Tokens are
+/// cached per registry, realm and scope until shortly before they expire.
+#[derive(Debug, Default)]
+pub struct Cache(Mutex<Entries>);
+
+impl Cache {
+    /// Tokens are refreshed once less than this fraction of their lifetime
+    /// remains, so that they don't expire while a request is in flight.
+    const MARGIN: u32 = 5;
+
+    pub fn global() -> &'static Self {
+        static CACHE: OnceLock<Cache> = OnceLock::new();
+        CACHE.get_or_init(Self::default)
+    }
+
+    /// The last challenge from the registry at `host`
+    pub fn challenge(&self, host: &str) -> Option<Challenge> {
+        self.0.lock().unwrap().challenges.get(host).cloned()
+    }
+
+    /// Remembers the challenge from the registry at `host`
+    pub fn set_challenge(&self, host: &str, challenge: Challenge) {
+        let mut lock = self.0.lock().unwrap();
+        lock.challenges.insert(host.into(), challenge);
+    }
+
+    /// The cached `Authorization` header for `key`, unless it is stale
+    pub fn token(&self, key: &Key) -> Option<String> {
+        self.token_at(key, Instant::now())
+    }
+
+    /// The cached `Authorization` header for `key`, unless it is stale at `now`
+    fn token_at(&self, key: &Key, now: Instant) -> Option<String> {
+        let lock = self.0.lock().unwrap();
+        match lock.tokens.get(key) {
+            Some((value, stale)) if now < *stale => Some(value.clone()),
+            _ => None,
+        }
+    }
+
+    /// Caches a token for `key` issued at `issued` and returns its header
+    pub fn insert(&self, key: Key, token: &Token, issued: Instant) -> Result<String> {
+        let value = token.bearer()?;
+        let lifetime = token.lifetime();
+        let stale = issued + lifetime - lifetime / Self::MARGIN;
+
+        let mut lock = self.0.lock().unwrap();
+        if let Some(refresh) = token.refresh_token() {
+            lock.refresh.insert(key.0.clone(), refresh.into());
+        }
+
+        lock.tokens.insert(key, (value.clone(), stale));
+        Ok(value)
+    }
+
+    /// Forgets the token for `key`, which the registry rejected
+    pub fn remove(&self, key: &Key) {
+        self.0.lock().unwrap().tokens.remove(key);
+    }
+
+    /// The latest refresh token issued for the registry at `host`
+    ///
+    /// Token services may rotate refresh tokens, so this supersedes the one
+    /// in the credentials.
+    pub fn refresh_token(&self, host: &str) -> Option<String> {
+        self.0.lock().unwrap().refresh.get(host).cloned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Cache, Challenge, Token};
+
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn challenge() {
+        let header = r#"Bearer realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/fedora:pull""#;
+        assert_eq!(
+            Challenge::parse(header).unwrap(),
+            vec![Challenge::Bearer {
+                realm: "https://auth.docker.io/token".into(),
+                service: Some("registry.docker.io".into()),
+                scope: Some("repository:library/fedora:pull".into()),
+            }]
+        );
+
+        let header = r#"basic realm="Harbor \"corp\"", Bearer realm=https://corp/token , error="insufficient_scope""#;
+        assert_eq!(
+            Challenge::parse(header).unwrap(),
+            vec![
+                Challenge::Basic,
+                Challenge::Bearer {
+                    realm: "https://corp/token".into(),
+                    service: None,
+                    scope: None,
+                }
+            ]
+        );
+
+        assert_eq!(Challenge::parse("Negotiate").unwrap(), vec![]);
+        assert!(Challenge::parse(r#"Bearer service="registry""#).is_err());
+        assert!(Challenge::parse(r#"Bearer realm="https://corp/token"#).is_err());
+        assert!(Challenge::parse(r#"Bearer realm="a"b"#).is_err());
+        assert!(Challenge::parse("=foo").is_err());
+    }
+
+    #[test]
+    fn token() {
+        let token: Token = serde_json::from_str(r#"{"access_token": "abc"}"#).unwrap();
+        assert_eq!(token.bearer().unwrap(), "Bearer abc");
+        assert_eq!(token.lifetime().as_secs(), 60);
+
+        let token: Token =
+            serde_json::from_str(r#"{"token": "xyz", "access_token": "xyz", "expires_in": 300}"#)
+                .unwrap();
+        assert_eq!(token.bearer().unwrap(), "Bearer xyz");
+        assert_eq!(token.lifetime().as_secs(), 300);
+
+        let token: Token = serde_json::from_str(r#"{"expires_in": 300}"#).unwrap();
+        assert!(token.bearer().is_err());
+    }
+
+    #[test]
+    fn cache() {
+        let cache = Cache::default();
+        let key = (
+            "quay.io".into(),
+            "https://quay.io/v2/auth".into(),
+            "pull".into(),
+        );
+        assert_eq!(cache.token(&key), None);
+
+        let token: Token = serde_json::from_str(r#"{"token": "abc", "expires_in": 300}"#).unwrap();
+        assert_eq!(
+            cache.insert(key.clone(), &token, Instant::now()).unwrap(),
+            "Bearer abc"
+        );
+        assert_eq!(cache.token(&key).unwrap(), "Bearer abc");
+
+        // Tokens are stale shortly before they expire.
+        let issued = Instant::now();
+        cache.insert(key.clone(), &token, issued).unwrap();
+        let later = |secs| issued + Duration::from_secs(secs);
+        assert_eq!(cache.token_at(&key, later(230)).unwrap(), "Bearer abc");
+        assert_eq!(cache.token_at(&key, later(250)), None);
+
+        cache.insert(key.clone(), &token, Instant::now()).unwrap();
+        cache.remove(&key);
+        assert_eq!(cache.token(&key), None);
+    }
+}
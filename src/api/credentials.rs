@@ -0,0 +1,509 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::endpoint::Endpoint;
+use super::registries::Registries;
+
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+struct Entry {
+    #[serde(default)]
+    auth: Option<String>,
+
+    #[serde(default)]
+    username: Option<String>,
+
+    #[serde(default)]
+    password: Option<String>,
+
+    #[serde(default, rename = "identitytoken")]
+    identity_token: Option<String>,
+}
+
+impl Entry {
+    /// The credentials in this entry
+    ///
+    /// Returns `None` if the entry is empty, which happens when the actual
+    /// credentials are kept by a credential helper.
+    fn credentials(&self) -> Result<Option<Credentials>> {
+        if let Some(token) = self.identity_token.as_deref().filter(|t| !t.is_empty()) {
+            return Ok(Some(Credentials::new(Credentials::TOKEN_USER, token)));
+        }
+
+        if let (Some(username), Some(password)) = (&self.username, &self.password) {
+            return Ok(Some(Credentials::new(username, password)));
+        }
+
+        let auth = self.auth.as_deref().unwrap_or_default().trim();
+        if auth.is_empty() {
+            return Ok(None);
+        }
+
+        let auth = String::from_utf8(base64::decode(auth)?)?;
+        match auth.split_once(':') {
+            Some((username, password)) => Ok(Some(Credentials::new(username, password))),
+            None => Err(anyhow!("invalid auth entry")),
+        }
+    }
+}
+
+/// A Docker credential helper (i.e. `docker-credential-<name>`)
+struct Helper;
+
+impl Helper {
+    const PREFIX: &'static str = "docker-credential-";
+    const NOT_FOUND: &'static str = "credentials not found";
+
+    /// Runs `<program> get` for `server` using the credential helper protocol
+    ///
+    /// Returns `None` if the helper has no credentials for the server.
+    fn get(program: impl AsRef<OsStr>, server: &str) -> Result<Option<Credentials>> {
+        #[derive(Deserialize)]
+        struct Reply {
+            #[serde(rename = "Username")]
+            username: String,
+
+            #[serde(rename = "Secret")]
+            secret: String,
+        }
+
+        let program = program.as_ref();
+        let mut child = Command::new(program)
+            .arg("get")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("unable to run {:?}", program))?;
+
+        // Ignore write errors; a helper may exit without reading its input.
+        let _ = child.stdin.take().unwrap().write_all(server.as_bytes());
+        let output = child.wait_with_output()?;
+
+        if !output.status.success() {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            if stdout.contains(Self::NOT_FOUND) {
+                return Ok(None);
+            }
+
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let msg = [stdout.trim(), stderr.trim()].join(" ");
+            return Err(anyhow!("{:?} failed: {}", program, msg.trim()));
+        }
+
+        let reply: Reply = serde_json::from_slice(&output.stdout)
+            .with_context(|| format!("invalid reply from {:?}", program))?;
+        Ok(Some(Credentials::new(reply.username, reply.secret)))
+    }
+}
+
+/// An auth file in the format shared by Docker and Podman
+#[derive(Clone, Debug, Default, Deserialize)]
+struct AuthFile {
+    #[serde(default)]
+    auths: HashMap<String, Entry>,
+
+    #[serde(default, rename = "credsStore")]
+    creds_store: Option<String>,
+
+    #[serde(default, rename = "credHelpers")]
+    cred_helpers: HashMap<String, String>,
+}
+
+impl AuthFile {
+    const DOCKER_HUB: &'static [&'static str] = &[
+        "docker.io",
+        "index.docker.io",
+        "registry-1.docker.io",
+        "registry.hub.docker.com",
+    ];
+
+    const DOCKER_HUB_SERVER: &'static str = "https://index.docker.io/v1/";
+
+    /// The auth files to search, in order of preference
+    fn paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        if let Some(path) = std::env::var_os("REGISTRY_AUTH_FILE") {
+            paths.push(path.into());
+        }
+
+        if let Some(path) = std::env::var_os("XDG_RUNTIME_DIR") {
+            paths.push(Path::new(&path).join("containers").join("auth.json"));
+        }
+
+        if let Some(path) = std::env::var_os("HOME") {
+            paths.push(Path::new(&path).join(".docker").join("config.json"));
+        }
+
+        paths
+    }
+
+    /// Loads the auth file at `path`, if it exists
+    fn load(path: &Path) -> Result<Option<Self>> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(
+                serde_json::from_reader(file).with_context(|| format!("{:?}", path))?,
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Normalizes a registry host or an auth file key for comparison
+    ///
+    /// Keys may be legacy URLs (i.e. `https://index.docker.io/v1/`), bare
+    /// hosts or a host followed by a repository namespace.
+    fn normalize(key: &str) -> String {
+        let key = match key.split_once("://") {
+            Some((.., rest)) => rest.split('/').next().unwrap_or_default(),
+            None => key.trim_end_matches('/'),
+        };
+
+        let (host, rest) = key.split_once('/').unwrap_or((key, ""));
+        let host = match Self::DOCKER_HUB.contains(&host) {
+            true => Self::DOCKER_HUB[0],
+            false => host,
+        };
+
+        match rest {
+            "" => host.into(),
+            rest => format!("{}/{}", host, rest),
+        }
+    }
+
+    /// Finds the credentials for `host/path` in this file
+    ///
+    /// A per-registry credential helper takes precedence over an entry in
+    /// `auths`, which in turn takes precedence over the default credential
+    /// store.
+    fn credentials(&self, host: &str, path: &str) -> Result<Option<Credentials>> {
+        let normalized = Self::normalize(host);
+
+        for (server, name) in self.cred_helpers.iter() {
+            if Self::normalize(server) == normalized {
+                if let Some(creds) = Helper::get(format!("{}{}", Helper::PREFIX, name), server)? {
+                    return Ok(Some(creds));
+                }
+            }
+        }
+
+        if let Some(entry) = self.find(host, path) {
+            if let Some(creds) = entry.credentials()? {
+                return Ok(Some(creds));
+            }
+        }
+
+        if let Some(name) = &self.creds_store {
+            // Docker stores the Docker Hub credentials under its legacy URL.
+            let server = match normalized == Self::DOCKER_HUB[0] {
+                true => Self::DOCKER_HUB_SERVER,
+                false => host,
+            };
+
+            return Helper::get(format!("{}{}", Helper::PREFIX, name), server);
+        }
+
+        Ok(None)
+    }
+
+    /// Edits the `auths` in the file at `path`, keeping everything else
+    ///
+    /// The file is only written if `edit` returns `true`.
+    fn edit(path: &Path, edit: impl FnOnce(&mut Map<String, Value>) -> bool) -> Result<bool> {
+        let mut file = match std::fs::read(path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).with_context(|| format!("{:?}", path))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Value::Object(Map::new()),
+            Err(e) => return Err(e).with_context(|| format!("{:?}", path)),
+        };
+
+        let auths = file
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("{:?}: not a JSON object", path))?
+            .entry("auths")
+            .or_insert_with(|| Value::Object(Map::new()))
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("{:?}: auths is not a JSON object", path))?;
+
+        if !edit(auths) {
+            return Ok(false);
+        }
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // Replace the file atomically, since it holds other tools' secrets.
+        let tmp = path.with_extension("tmp");
+        let mut out = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp)
+            .with_context(|| format!("{:?}", tmp))?;
+        serde_json::to_writer_pretty(&mut out, &file)?;
+        out.write_all(b"\n")?;
+        std::fs::rename(&tmp, path).with_context(|| format!("{:?}", path))?;
+
+        Ok(true)
+    }
+
+    /// Removes the entries whose keys normalize to `key`
+    ///
+    /// Returns whether there were any.
+    fn remove(auths: &mut Map<String, Value>, key: &str) -> bool {
+        let keys: Vec<_> = auths
+            .keys()
+            .filter(|k| Self::normalize(k) == key)
+            .cloned()
+            .collect();
+
+        for k in keys.iter() {
+            auths.remove(k);
+        }
+
+        !keys.is_empty()
+    }
+
+    /// Finds the most specific entry matching the repository at `host/path`
+    fn find(&self, host: &str, path: &str) -> Option<&Entry> {
+        let name = Self::normalize(&format!("{}/{}", host, path));
+
+        self.auths
+            .iter()
+            .map(|(key, entry)| (Self::normalize(key), entry))
+            .filter(|(key, ..)| {
+                name == *key || name.starts_with(key) && name[key.len()..].starts_with('/')
+            })
+            .max_by_key(|(key, ..)| key.len())
+            .map(|(.., entry)| entry)
+    }
+}
+
+/// A username and password for a registry
+#[derive(Clone)]
+pub struct Credentials {
+    username: String,
+    password: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    /// The username that marks the password as an OAuth2 refresh token
+    const TOKEN_USER: &'static str = "<token>";
+
+    pub fn new(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            username: username.into(),
+            password: password.into(),
+        }
+    }
+
+    /// Finds the credentials for the repository at `host/path`
+    ///
+    /// Returns `None` if neither the auth files nor their credential helpers
+    /// have an entry for the registry.
+    pub fn lookup(host: &str, path: &str) -> Result<Option<Self>> {
+        for file in AuthFile::paths() {
+            if let Some(auths) = AuthFile::load(&file)? {
+                let creds = auths.credentials(host, path);
+                if let Some(creds) = creds.with_context(|| format!("{:?}", file))? {
+                    return Ok(Some(creds));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// The OAuth2 refresh token, if these credentials are an identity token
+    pub fn refresh_token(&self) -> Option<&str> {
+        match self.username == Self::TOKEN_USER {
+            true => Some(&self.password),
+            false => None,
+        }
+    }
+
+    /// The value of an `Authorization` header using the Basic scheme
+    pub fn basic(&self) -> String {
+        let pair = format!("{}:{}", self.username, self.password);
+        format!("Basic {}", base64::encode(pair))
+    }
+
+    /// The auth file that new credentials are stored in
+    ///
+    /// This is the first of the files that we search.
+    pub fn default_file() -> Result<PathBuf> {
+        AuthFile::paths()
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("no auth file; set REGISTRY_AUTH_FILE"))
+    }
+
+    /// Checks these credentials against `registry` (format: `host[/namespace]`)
+    pub fn check(&self, registry: &str) -> Result<()> {
+        let (host, path) = registry.split_once('/').unwrap_or((registry, ""));
+
+        // Check against the registry itself, rather than any of its mirrors.
+        let locations = Registries::global()?.resolve(host, path)?;
+        let (location, ..) = locations
+            .last()
+            .ok_or_else(|| anyhow!("{}: no location", registry))?;
+
+        Endpoint::new(location, false)?.login(self.clone())
+    }
+
+    /// Stores these credentials for `registry` in the auth file at `path`
+    ///
+    /// Any other entries for the same registry are replaced.
+    pub fn store(&self, registry: &str, path: &Path) -> Result<()> {
+        let key = AuthFile::normalize(registry);
+        let pair = format!("{}:{}", self.username, self.password);
+
+        AuthFile::edit(path, |auths| {
+            AuthFile::remove(auths, &key);
+            auths.insert(key.clone(), json!({ "auth": base64::encode(pair) }));
+            true
+        })?;
+
+        // Credential helpers for the registry take precedence over `auths`.
+        if let Some(file) = AuthFile::load(path)? {
+            if file
+                .cred_helpers
+                .keys()
+                .any(|k| AuthFile::normalize(k) == key)
+            {
+                warn!("{:?}: a credential helper is configured for {}", path, key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Removes the credentials for `registry` from the auth file at `path`
+    ///
+    /// Returns whether there were any.
+    pub fn erase(registry: &str, path: &Path) -> Result<bool> {
+        let key = AuthFile::normalize(registry);
+
+        AuthFile::edit(path, |auths| AuthFile::remove(auths, &key))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthFile, Credentials, Helper};
+
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn find() {
+        let auths: AuthFile = serde_json::from_str(
+            r#"{"auths": {
+                "https://index.docker.io/v1/": {"auth": "aHViOnNlY3JldA=="},
+                "quay.io": {"username": "quay", "password": "secret"},
+                "quay.io/corp": {"auth": "Y29ycDpzZWNyZXQ="}
+            }}"#,
+        )
+        .unwrap();
+
+        let find = |host, path| {
+            auths
+                .find(host, path)
+                .unwrap()
+                .credentials()
+                .unwrap()
+                .unwrap()
+        };
+        assert_eq!(
+            find("registry.hub.docker.com", "library/fedora").username,
+            "hub"
+        );
+        assert_eq!(find("quay.io", "fedora/fedora").username, "quay");
+        assert_eq!(find("quay.io", "corp/boot").username, "corp");
+        assert_eq!(find("quay.io", "corporate/boot").username, "quay");
+        assert!(auths.find("ghcr.io", "corp/boot").is_none());
+    }
+
+    #[test]
+    fn helper() {
+        const STUB: &str = r#"#!/bin/sh
+[ "$1" = get ] || exit 1
+read server
+case "$server" in
+    registry.corp) echo '{"ServerURL":"registry.corp","Username":"corp","Secret":"s3cret"}' ;;
+    *) echo "credentials not found in native keychain"; exit 1 ;;
+esac
+"#;
+
+        let name = format!("docker-credential-stub-{}", std::process::id());
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, STUB).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let creds = Helper::get(&path, "registry.corp").unwrap().unwrap();
+        assert_eq!(creds.username, "corp");
+        assert_eq!(creds.password, "s3cret");
+        assert!(Helper::get(&path, "quay.io").unwrap().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn store() {
+        let path = std::env::temp_dir().join(format!("wyrcan-auth-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"auths": {"https://index.docker.io/v1/": {"auth": "b2xkOm9sZA=="}}, "detachKeys": "ctrl-q"}"#,
+        )
+        .unwrap();
+
+        Credentials::new("hub", "secret")
+            .store("docker.io", &path)
+            .unwrap();
+        Credentials::new("quay", "secret")
+            .store("quay.io", &path)
+            .unwrap();
+
+        let file: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(file["detachKeys"], "ctrl-q");
+        assert_eq!(file["auths"].as_object().unwrap().len(), 2);
+
+        let auths = AuthFile::load(&path).unwrap().unwrap();
+        let creds = auths.find("registry.hub.docker.com", "library/fedora");
+        assert_eq!(
+            creds.unwrap().credentials().unwrap().unwrap().username,
+            "hub"
+        );
+
+        assert!(Credentials::erase("docker.io", &path).unwrap());
+        assert!(!Credentials::erase("docker.io", &path).unwrap());
+
+        let auths = AuthFile::load(&path).unwrap().unwrap();
+        assert!(auths.find("docker.io", "library/fedora").is_none());
+        assert!(auths.find("quay.io", "fedora/fedora").is_some());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
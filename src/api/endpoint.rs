@@ -0,0 +1,460 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::agent;
+use super::auth::{Cache, Challenge, Token};
+use super::registries::Location;
+use super::retry::retry;
+use super::Credentials;
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use ureq::{Agent, Response};
+use url::Url;
+
+/// A registry location that a repository can be pulled from
+///
+/// This is either the repository's registry itself or one of its mirrors.
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    location: String,
+    host: String,
+    path: String,
+    digest_only: bool,
+    credentials: Arc<OnceLock<Option<Credentials>>>,
+    schemes: &'static [&'static str],
+    fallback: Arc<AtomicUsize>,
+    agent: Agent,
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.host, self.path)
+    }
+}
+
+impl Endpoint {
+    const CLIENT_ID: &'static str = "wyrcan";
+
+    const ALIASES: &'static [(&'static str, &'static str)] =
+        &[("docker.io", "registry.hub.docker.com")];
+
+    /// Creates the endpoint for `location`, which is in the form `host/path`
+    ///
+    /// If `digest_only`, the endpoint is only used for content that is
+    /// addressed by digest.
+    pub fn new(location: &Location, digest_only: bool) -> Result<Self> {
+        let (mut host, path) = location
+            .location
+            .split_once('/')
+            .ok_or_else(|| anyhow!("invalid location: {}", location.location))?;
+
+        // Substitute the aliases
+        for (from, into) in Self::ALIASES {
+            if host == *from {
+                host = *into;
+                break;
+            }
+        }
+
+        Ok(Self {
+            location: location.location.clone(),
+            host: host.into(),
+            path: path.into(),
+            digest_only,
+            credentials: Default::default(),
+            schemes: location.schemes(),
+            fallback: Arc::new(AtomicUsize::new(0)),
+            agent: agent::shared(location, host)?,
+        })
+    }
+
+    /// The location of this endpoint as configured, i.e. before any alias
+    pub fn location(&self) -> &str {
+        &self.location
+    }
+
+    /// Whether this endpoint may serve the content at `path`
+    pub fn serves(&self, path: &str) -> bool {
+        let by_digest = match path.split_once('/') {
+            Some(("blobs", ..)) => true,
+            Some(("manifests", reference)) => reference.contains(':'),
+            _ => false,
+        };
+
+        by_digest || !self.digest_only
+    }
+
+    /// The credentials for this endpoint, if any
+    ///
+    /// These are only looked up once the registry challenges us, so that
+    /// credential helpers aren't run for registries that don't need them. If
+    /// the lookup fails, we carry on without credentials: public images can
+    /// still be pulled.
+    fn credentials(&self) -> Option<&Credentials> {
+        self.credentials
+            .get_or_init(|| match Credentials::lookup(&self.host, &self.path) {
+                Ok(credentials) => credentials,
+                Err(e) => {
+                    warn!("{}: ignoring credentials: {:#}", self, e);
+                    None
+                }
+            })
+            .as_ref()
+    }
+
+    /// The scope needed to pull from this repository
+    fn scope(&self) -> String {
+        format!("repository:{}:pull", self.path)
+    }
+
+    /// Answers the challenges in a `Www-Authenticate` header
+    ///
+    /// The chosen challenge is remembered for later requests. Returns the
+    /// scope that the registry asked for, if any.
+    fn challenge(&self, wwwauth: &str) -> Result<Option<String>> {
+        let challenges = Challenge::parse(wwwauth)?;
+
+        // Prefer tokens over sending the credentials themselves.
+        let bearer = challenges
+            .iter()
+            .find(|c| matches!(c, Challenge::Bearer { .. }));
+
+        match bearer.or_else(|| challenges.first()).cloned() {
+            Some(Challenge::Bearer {
+                realm,
+                service,
+                scope,
+            }) => {
+                let challenge = Challenge::Bearer {
+                    realm,
+                    service,
+                    scope: None,
+                };
+
+                Cache::global().set_challenge(&self.host, challenge);
+                Ok(scope)
+            }
+
+            Some(challenge) => {
+                Cache::global().set_challenge(&self.host, challenge);
+                Ok(None)
+            }
+
+            None => Err(anyhow!("{}: unsupported challenge: {}", self, wwwauth)),
+        }
+    }
+
+    /// The value of the `Authorization` header for a request
+    ///
+    /// Returns `None` until the registry has challenged us. If `rejected`,
+    /// the cached token was refused and a fresh one is fetched.
+    fn authorization(&self, scope: Option<String>, rejected: bool) -> Result<Option<String>> {
+        match Cache::global().challenge(&self.host) {
+            None => Ok(None),
+
+            Some(Challenge::Basic) => match self.credentials() {
+                Some(creds) if creds.refresh_token().is_none() => Ok(Some(creds.basic())),
+                _ => Err(anyhow!("{}: registry requires credentials", self)),
+            },
+
+            Some(Challenge::Bearer { realm, service, .. }) => {
+                let scope = scope.unwrap_or_else(|| self.scope());
+                let key = (self.host.clone(), realm.clone(), scope.clone());
+
+                if rejected {
+                    Cache::global().remove(&key);
+                } else if let Some(token) = Cache::global().token(&key) {
+                    return Ok(Some(token));
+                }
+
+                let issued = Instant::now();
+                let token = self.token(&realm, service.as_deref(), Some(&scope))?;
+                Ok(Some(Cache::global().insert(key, &token, issued)?))
+            }
+        }
+    }
+
+    /// Fetches a token for `scope` from the token service at `realm`
+    ///
+    /// Refresh tokens are exchanged using the OAuth2 POST flow. Otherwise,
+    /// the token is fetched with a GET, using the credentials, if any.
+    fn token(&self, realm: &str, service: Option<&str>, scope: Option<&str>) -> Result<Token> {
+        let refresh = match Cache::global().refresh_token(&self.host) {
+            Some(refresh) => Some(refresh),
+            None => self
+                .credentials()
+                .and_then(|c| c.refresh_token())
+                .map(String::from),
+        };
+
+        // The token service is often on a host of its own.
+        let mut url = Url::parse(realm).with_context(|| format!("invalid realm: {}", realm))?;
+        let agent = match agent::host(&url) == self.host {
+            true => self.agent.clone(),
+            false => agent::foreign(&url)?,
+        };
+
+        let rep = if let Some(refresh) = refresh.as_deref() {
+            let mut form = vec![
+                ("grant_type", "refresh_token"),
+                ("refresh_token", refresh),
+                ("client_id", Self::CLIENT_ID),
+            ];
+
+            if let Some(scope) = scope {
+                form.push(("scope", scope));
+            }
+
+            if let Some(service) = service {
+                form.push(("service", service));
+            }
+
+            retry(self, || agent::post_form(&agent, url.as_str(), &form))?
+        } else {
+            if let Some(scope) = scope {
+                url.query_pairs_mut().append_pair("scope", scope);
+            }
+
+            if let Some(service) = service {
+                url.query_pairs_mut().append_pair("service", service);
+            }
+
+            let basic = self.credentials().map(|c| c.basic());
+            let headers: Vec<_> = basic
+                .iter()
+                .map(|b| ("Authorization", b.as_str()))
+                .collect();
+            retry(self, || {
+                agent::request(&agent, "GET", url.as_str(), &headers)
+            })?
+        };
+
+        Ok(rep.into_json()?)
+    }
+
+    /// Sends a `method` request for `path` under `/v2/`, trying each URL scheme
+    ///
+    /// We only move on to the next scheme if the connection itself failed.
+    /// Once we have fallen back, later requests start from that scheme.
+    fn call(
+        &self,
+        method: &str,
+        path: &str,
+        headers: &[(&str, &str)],
+    ) -> Result<Response, Box<ureq::Error>> {
+        let first = self.fallback.load(Ordering::Relaxed);
+        let mut result = None;
+
+        for (i, scheme) in self.schemes.iter().enumerate().skip(first) {
+            if let Some(Err(e)) = result.take() {
+                warn!("{}: falling back to {}: {}", self, scheme, e);
+            }
+
+            let url = format!("{}://{}/v2/{}", scheme, self.host, path);
+            match agent::request(&self.agent, method, &url, headers) {
+                Err(e) if matches!(*e, ureq::Error::Transport(..)) => result = Some(Err(e)),
+                other => {
+                    self.fallback.store(i, Ordering::Relaxed);
+                    return other;
+                }
+            }
+        }
+
+        result.unwrap_or_else(|| {
+            let msg = format!("{}: no URL scheme left to try", self);
+            Err(Box::new(std::io::Error::other(msg).into()))
+        })
+    }
+
+    /// Sends a `method` request, answering any authentication challenge
+    pub fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let path = format!("{}/{}", self.path, path);
+        let path = path.as_str();
+
+        let mut authorization = self.authorization(None, false)?;
+        let mut retried = false;
+        loop {
+            let mut headers = headers.to_vec();
+            if let Some(authorization) = &authorization {
+                headers.push(("Authorization", authorization));
+            }
+
+            // Answer the challenge. If we were already authorized, our token
+            // was rejected, so retry just once with a fresh one.
+            match retry(self, || self.call(method, path, &headers)).map_err(|e| *e) {
+                Err(ureq::Error::Status(401, rep)) if !retried && rep.has("Www-Authenticate") => {
+                    let rejected = authorization.is_some();
+                    let scope = self.challenge(rep.header("Www-Authenticate").unwrap())?;
+                    authorization = self.authorization(scope, rejected)?;
+                    retried = rejected;
+                }
+
+                Ok(rep) => return Ok(rep),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Checks `credentials` against the registry
+    ///
+    /// As with `docker login`, this answers the challenge from `/v2/` and, for
+    /// registries with a token service, fetches a token without any scope.
+    pub fn login(mut self, credentials: Credentials) -> Result<()> {
+        self.credentials = Arc::new(OnceLock::from(Some(credentials)));
+
+        let invalid = |e: ureq::Error| match e {
+            ureq::Error::Status(401 | 403, ..) => {
+                anyhow!("{}: invalid username or password", self.host)
+            }
+            e => e.into(),
+        };
+
+        let rep = match retry(&self, || self.call("GET", "", &[])).map_err(|e| *e) {
+            Err(ureq::Error::Status(401, rep)) if rep.has("Www-Authenticate") => rep,
+            Err(e) => return Err(e.into()),
+            Ok(..) => {
+                warn!("{}: registry does not require credentials", self);
+                return Ok(());
+            }
+        };
+
+        self.challenge(rep.header("Www-Authenticate").unwrap())?;
+        let authorization = match Cache::global().challenge(&self.host) {
+            Some(Challenge::Bearer { realm, service, .. }) => {
+                let token = self.token(&realm, service.as_deref(), None);
+                token
+                    .map_err(|e| match e.downcast::<Box<ureq::Error>>() {
+                        Ok(e) => invalid(*e),
+                        Err(e) => e,
+                    })?
+                    .bearer()?
+            }
+
+            _ => self.authorization(None, false)?.unwrap_or_default(),
+        };
+
+        let headers = [("Authorization", authorization.as_str())];
+        retry(&self, || self.call("GET", "", &headers)).map_err(|e| invalid(*e))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Endpoint;
+    use crate::api::agent::test::{reply, Server};
+    use crate::api::auth::{Cache, Challenge, Token};
+    use crate::api::registries::Location;
+
+    use std::sync::atomic::Ordering;
+    use std::sync::{Arc, OnceLock};
+    use std::time::Instant;
+
+    /// The endpoint for `location`, which has no credentials
+    fn endpoint(location: Location) -> Endpoint {
+        let mut endpoint = Endpoint::new(&location, false).unwrap();
+        endpoint.credentials = Arc::new(OnceLock::from(None));
+        endpoint
+    }
+
+    #[test]
+    fn fallback() {
+        let server = Server::new();
+        let location = Location {
+            insecure: true,
+            ..Location::from(format!("{}/foo", server.host()))
+        };
+        let ok = || reply("200 OK", &[], b"{}");
+        let server = server.serve(vec![ok(), ok()]);
+
+        // The server doesn't speak TLS, so we fall back to plain HTTP and
+        // then stay there.
+        let endpoint = endpoint(location);
+        endpoint.call("GET", "foo/tags/list", &[]).unwrap();
+        endpoint.call("GET", "foo/tags/list", &[]).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0], "TLS");
+        assert!(requests[1].starts_with("GET /v2/foo/tags/list "));
+        assert!(requests[2].starts_with("GET /v2/foo/tags/list "));
+
+        // With no scheme left to try, we fail rather than panic.
+        endpoint.fallback.store(2, Ordering::Relaxed);
+        assert!(endpoint.call("GET", "foo/tags/list", &[]).is_err());
+    }
+
+    /// Sends a request with a token that the registry no longer accepts
+    ///
+    /// The registry then gives us `fresh` from its token service and answers
+    /// the retry with `last`. Returns the result and the requests sent.
+    fn rejected(fresh: &str, last: &str) -> (anyhow::Result<()>, Vec<String>) {
+        let server = Server::new();
+        let host = server.host();
+        let location = Location {
+            plain_http: true,
+            ..Location::from(format!("{}/foo", host))
+        };
+
+        let realm = format!("http://{}/token", host);
+        let challenge = format!(r#"Bearer realm="{}""#, realm);
+        let unauthorized = || {
+            reply(
+                "401 Unauthorized",
+                &[("Www-Authenticate", &challenge)],
+                b"{}",
+            )
+        };
+        let token = format!(r#"{{"token": "{}"}}"#, fresh);
+        let last = match last {
+            "200 OK" => reply(last, &[], b"{}"),
+            _ => unauthorized(),
+        };
+        let server = server.serve(vec![
+            unauthorized(),
+            reply("200 OK", &[], token.as_bytes()),
+            last,
+        ]);
+
+        let endpoint = endpoint(location);
+        let bearer = Challenge::Bearer {
+            realm: realm.clone(),
+            service: None,
+            scope: None,
+        };
+        Cache::global().set_challenge(&host, bearer);
+
+        let stale: Token = serde_json::from_str(r#"{"token": "stale"}"#).unwrap();
+        let key = (host, realm, endpoint.scope());
+        Cache::global().insert(key, &stale, Instant::now()).unwrap();
+
+        let result = endpoint.request("GET", "tags/list", &[]).map(|_| ());
+        (result, server.join().unwrap())
+    }
+
+    #[test]
+    fn retry() {
+        // The stale token is refused, so we fetch a fresh one and retry.
+        let (result, requests) = rejected("fresh", "200 OK");
+        result.unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[0].contains("Authorization: Bearer stale"));
+        assert!(requests[1].starts_with("GET /token?scope=repository%3Afoo%3Apull "));
+        assert!(requests[2].contains("Authorization: Bearer fresh"));
+
+        // If the fresh token is refused too, we give up after that one retry.
+        let (result, requests) = rejected("fresh", "401 Unauthorized");
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ureq::Error>(),
+            Some(ureq::Error::Status(401, ..))
+        ));
+        assert_eq!(requests.len(), 3);
+    }
+}
@@ -1,82 +1,445 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Repository;
-use crate::formats::{docker::v2::Layer, Manifest};
+use super::{Platform, Source};
+use crate::formats::{docker::v2::Layer, Digest, ImageConfig, Manifest};
 
 use std::fmt::Display;
+use std::io::Read;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 
 #[derive(Clone, Debug)]
 pub struct Image {
-    repo: Repository,
+    source: Arc<dyn Source>,
     manifest: Manifest,
     tag: String,
+    digest: Digest,
 }
 
 impl Display for Image {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}/{}", self.repo, self.tag)
+        write!(f, "{}/{}", self.source, self.tag)
     }
 }
 
 impl Image {
-    pub(super) fn new(repo: Repository, tag: &str) -> Result<Self> {
-        let path = format!("manifests/{}", tag);
-        let rep = repo.get(&path, &[])?;
-
-        Ok(Image {
-            manifest: rep.into_json()?,
-            repo,
-            tag: tag.into(),
-        })
+    /// Manifest lists nested deeper than this are rejected
+    const MAX_DEPTH: usize = 8;
+
+    /// Opens the image for `tag`
+    ///
+    /// Manifest lists are resolved to the image for `platform`.
+    pub fn new(source: Arc<dyn Source>, tag: &str, platform: &Platform) -> Result<Self> {
+        let mut reference = tag.to_string();
+
+        for _ in 0..Self::MAX_DEPTH {
+            let mut fetched = source.manifest(&reference)?;
+            let mut bytes = Vec::new();
+            fetched.reader.read_to_end(&mut bytes)?;
+
+            let manifest = Manifest::parse(fetched.media_type.as_deref(), &bytes)
+                .with_context(|| format!("{}/{}", source, reference))?;
+
+            // Signed schema 1 manifests are identified by their payload (i.e.
+            // without the signatures), which is also all that we trust.
+            let (manifest, payload) = match manifest {
+                Manifest::DockerV1(m) if !m.signatures.is_empty() => {
+                    let payload = m
+                        .payload(&bytes)
+                        .with_context(|| format!("{}/{}", source, reference))?;
+                    let manifest = Manifest::parse(Some(Manifest::DOCKER_V1), &payload)?;
+                    (manifest, payload)
+                }
+
+                manifest => (manifest, bytes.clone()),
+            };
+
+            // A digest in the reference is authoritative. Otherwise, check
+            // the digest that the source claims, if any.
+            let digest = match reference.parse::<Digest>().ok().or(fetched.digest) {
+                Some(digest) if digest.verify(&payload) || digest.verify(&bytes) => digest,
+                Some(digest) => {
+                    return Err(anyhow!(
+                        "{}/{}: manifest does not match digest {}",
+                        source,
+                        reference,
+                        digest
+                    ))
+                }
+                None => Digest::sha256(&payload),
+            };
+
+            // Each entry is (media type, digest, platform).
+            let entries: Vec<_> = match manifest {
+                Manifest::DockerV2List(list) => list
+                    .manifests
+                    .into_iter()
+                    .map(|m| {
+                        let p = m.platform;
+                        let p = Platform::new(&p.os, &p.architecture, p.variant.as_deref());
+                        (m.media_type, m.digest, Some(p))
+                    })
+                    .collect(),
+
+                Manifest::OciIndex(index) => index
+                    .manifests
+                    .into_iter()
+                    .map(|m| {
+                        let p = m.platform.as_ref();
+                        let p =
+                            p.map(|p| Platform::new(&p.os, &p.architecture, p.variant.as_deref()));
+                        (Some(m.media_type), m.digest, p)
+                    })
+                    .collect(),
+
+                // Whether we got here through a list or not, make sure that
+                // the image is really for the platform that we asked for.
+                manifest => {
+                    let image = Image {
+                        manifest,
+                        source,
+                        tag: tag.into(),
+                        digest,
+                    };
+
+                    let found = image.platform()?;
+                    if !platform.matches(&found) {
+                        return Err(anyhow!(
+                            "{}: image is for {}, not {}",
+                            image,
+                            found,
+                            platform
+                        ));
+                    }
+
+                    return Ok(image);
+                }
+            };
+
+            // Prefer an image for our platform. Failing that, descend into a
+            // nested list that doesn't say which platforms it covers.
+            let found = entries
+                .iter()
+                .find(|(.., p)| p.as_ref().map(|p| platform.matches(p)) == Some(true))
+                .or_else(|| {
+                    entries.iter().find(|(mt, .., p)| {
+                        let list = [Manifest::DOCKER_V2_LIST, Manifest::OCI_INDEX];
+                        p.is_none() && list.contains(&mt.as_deref().unwrap_or_default())
+                    })
+                });
+
+            reference = match found {
+                Some((_, digest, _)) => digest.to_string(),
+                None => {
+                    let available: Vec<_> = entries
+                        .iter()
+                        .filter_map(|(.., p)| p.as_ref().map(|p| p.to_string()))
+                        .collect();
+
+                    return Err(anyhow!(
+                        "{}/{}: no image for {} (available: {})",
+                        source,
+                        tag,
+                        platform,
+                        available.join(", ")
+                    ));
+                }
+            };
+        }
+
+        Err(anyhow!(
+            "{}/{}: manifest lists nested too deeply",
+            source,
+            tag
+        ))
     }
 
-    pub fn layers(&self) -> Result<Vec<super::Layer>> {
-        const DEFAULT: &'static str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+    /// The digest of the image manifest, which pins the image
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
 
-        Ok(match &self.manifest {
+    /// The platform that the image is for
+    pub fn platform(&self) -> Result<Platform> {
+        match &self.manifest {
+            // Schema 1 manifests only give the architecture and predate
+            // images for anything but Linux.
+            Manifest::DockerV1(m) => Ok(Platform::new("linux", &m.architecture, None)),
+
+            _ => {
+                let config = self.config()?;
+                let variant = config.variant.as_deref();
+                Ok(Platform::new(&config.os, &config.architecture, variant))
+            }
+        }
+    }
+
+    /// Config blobs larger than this are rejected
+    const MAX_CONFIG: u64 = 16 << 20;
+
+    /// Fetches the configuration of the image
+    ///
+    /// The config blob is validated against the digest in the manifest.
+    pub fn config(&self) -> Result<ImageConfig> {
+        let (media_type, digest) = match &self.manifest {
+            Manifest::DockerV2(m) => (m.config.media_type.as_deref(), &m.config.digest),
+            Manifest::Oci(m) => (Some(&*m.config.media_type), &m.config.digest),
+
+            Manifest::DockerV1(..) => {
+                return Err(anyhow!("{}: schema 1 images have no config", self))
+            }
+
+            Manifest::DockerV2List(..) | Manifest::OciIndex(..) => {
+                return Err(anyhow!("{}: manifest lists are not supported", self))
+            }
+        };
+
+        match media_type {
+            None | Some(ImageConfig::DOCKER | ImageConfig::OCI) => (),
+            Some(other) => return Err(anyhow!("{}: unsupported config type: {}", self, other)),
+        }
+
+        let (.., reader) = self.source.blob(digest)?;
+        let mut bytes = Vec::new();
+        reader.take(Self::MAX_CONFIG).read_to_end(&mut bytes)?;
+
+        if !digest.verify(&bytes) {
+            return Err(anyhow!("{}: config does not match digest {}", self, digest));
+        }
+
+        let config: ImageConfig =
+            serde_json::from_slice(&bytes).with_context(|| format!("{}: invalid config", self))?;
+
+        if config.rootfs.kind != "layers" {
+            let kind = config.rootfs.kind;
+            return Err(anyhow!("{}: unsupported rootfs type: {}", self, kind));
+        }
+
+        Ok(config)
+    }
+
+    pub fn layers(&self) -> Result<Vec<super::Layer>> {
+        let levels: Vec<_> = match &self.manifest {
+            // Schema 1 doesn't say how layers are compressed, so leave that
+            // to the data.
             Manifest::DockerV1(m) => m
-                .layers
-                .iter()
-                .map(|l| {
-                    super::Layer::new(
-                        self.repo.clone(),
-                        Layer {
-                            media_type: Some(DEFAULT.into()),
-                            size: 0,
-                            digest: l.digest.clone(),
-                            urls: Vec::new(),
-                        },
-                    )
+                .diffs()
+                .with_context(|| format!("{}: invalid schema 1 manifest", self))?
+                .into_iter()
+                .map(|l| Layer {
+                    media_type: None,
+                    size: 0,
+                    digest: l.digest.clone(),
+                    urls: Vec::new(),
                 })
                 .collect(),
 
-            Manifest::DockerV2(m) => m
-                .layers
-                .iter()
-                .cloned()
-                .map(|l| super::Layer::new(self.repo.clone(), l))
-                .collect(),
+            Manifest::DockerV2(m) => m.layers.clone(),
 
-            Manifest::DockerV2List(..) => panic!(),
+            Manifest::DockerV2List(..) | Manifest::OciIndex(..) => {
+                return Err(anyhow!("{}: manifest lists are not supported", self))
+            }
 
             Manifest::Oci(m) => m
                 .layers
                 .iter()
-                .map(|l| {
-                    super::Layer::new(
-                        self.repo.clone(),
-                        Layer {
-                            media_type: Some(l.media_type.clone()),
-                            size: l.size,
-                            digest: l.digest.clone(),
-                            urls: l.urls.clone(),
-                        },
-                    )
+                .map(|l| Layer {
+                    media_type: Some(l.media_type.clone()),
+                    size: l.size,
+                    digest: l.digest.clone(),
+                    urls: l.urls.clone(),
                 })
                 .collect(),
-        })
+        };
+
+        // Schema 1 images have no config, so there is nothing to check the
+        // uncompressed layers against.
+        let diff_ids = match &self.manifest {
+            Manifest::DockerV1(..) => vec![None; levels.len()],
+            _ => {
+                let diff_ids = self.config()?.rootfs.diff_ids;
+                if diff_ids.len() != levels.len() {
+                    return Err(anyhow!(
+                        "{}: config has {} diff_ids for {} layers",
+                        self,
+                        diff_ids.len(),
+                        levels.len()
+                    ));
+                }
+
+                diff_ids.into_iter().map(Some).collect()
+            }
+        };
+
+        Ok(levels
+            .into_iter()
+            .zip(diff_ids)
+            .map(|(level, diff_id)| super::Layer::new(self.source.clone(), level, diff_id))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Image;
+    use crate::api::source::test::Memory;
+    use crate::api::Platform;
+    use crate::formats::{Digest, Manifest};
+
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    /// The config of an image for `os` on `architecture`
+    fn config(os: &str, architecture: &str) -> Vec<u8> {
+        let config = json!({
+            "architecture": architecture,
+            "os": os,
+            "rootfs": { "type": "layers", "diff_ids": [] },
+        });
+
+        config.to_string().into_bytes()
+    }
+
+    /// The manifest of an image with `config` and no layers
+    fn manifest(config: &[u8]) -> Vec<u8> {
+        let manifest = json!({
+            "schemaVersion": 2,
+            "mediaType": Manifest::DOCKER_V2,
+            "config": {
+                "mediaType": "application/vnd.docker.container.image.v1+json",
+                "size": config.len(),
+                "digest": Digest::sha256(config).to_string(),
+            },
+            "layers": [],
+        });
+
+        manifest.to_string().into_bytes()
+    }
+
+    /// An image index of `(manifest, os/architecture)` entries
+    fn index(entries: &[(&[u8], Option<&str>)]) -> Vec<u8> {
+        let manifests: Vec<_> = entries
+            .iter()
+            .map(|(manifest, platform)| {
+                let media_type = match Manifest::parse(None, manifest).unwrap() {
+                    Manifest::OciIndex(..) => Manifest::OCI_INDEX,
+                    _ => Manifest::DOCKER_V2,
+                };
+
+                let mut entry = json!({
+                    "mediaType": media_type,
+                    "size": manifest.len(),
+                    "digest": Digest::sha256(manifest).to_string(),
+                });
+
+                if let Some((os, architecture)) = platform.and_then(|p| p.split_once('/')) {
+                    entry["platform"] = json!({ "os": os, "architecture": architecture });
+                }
+
+                entry
+            })
+            .collect();
+
+        let index = json!({
+            "schemaVersion": 2,
+            "mediaType": Manifest::OCI_INDEX,
+            "manifests": manifests,
+        });
+
+        index.to_string().into_bytes()
+    }
+
+    #[test]
+    fn digests() {
+        let amd64 = Platform::new("linux", "amd64", None);
+        let blob = config("linux", "amd64");
+        let image = manifest(&blob);
+        let digest = Digest::sha256(&image);
+        let other = Digest::sha256(b"something else");
+        let open = |source: Memory, reference: &str| {
+            Image::new(Arc::new(source), reference, &amd64).map(|i| i.digest().to_string())
+        };
+
+        // The digest that the source claims for a tag is checked...
+        let source = Memory::default().with_blob(&blob).with_manifest(
+            "latest",
+            Manifest::DOCKER_V2,
+            Some(digest.clone()),
+            &image,
+        );
+        assert_eq!(open(source, "latest").unwrap(), digest.to_string());
+
+        let source = Memory::default().with_manifest(
+            "latest",
+            Manifest::DOCKER_V2,
+            Some(other.clone()),
+            &image,
+        );
+        let err = open(source, "latest").unwrap_err();
+        assert!(err.to_string().contains("does not match digest"));
+
+        // ...as is the digest in a reference...
+        let source =
+            Memory::default().with_manifest(&other.to_string(), Manifest::DOCKER_V2, None, &image);
+        let err = open(source, &other.to_string()).unwrap_err();
+        assert!(err.to_string().contains("does not match digest"));
+
+        // ...and the digest of each manifest in a list.
+        let tampered = manifest(&config("linux", "arm64"));
+        let list = index(&[(&image, Some("linux/amd64"))]);
+        let source = Memory::default()
+            .with_manifest("latest", Manifest::OCI_INDEX, None, &list)
+            .with_manifest(&digest.to_string(), Manifest::DOCKER_V2, None, &tampered);
+        let err = open(source, "latest").unwrap_err();
+        assert!(err.to_string().contains("does not match digest"));
+    }
+
+    #[test]
+    fn platforms() {
+        let (amd64, arm64) = (config("linux", "amd64"), config("linux", "arm64"));
+        let (x86, arm) = (manifest(&amd64), manifest(&arm64));
+        let list = index(&[(&x86, Some("linux/amd64")), (&arm, Some("linux/arm64"))]);
+        let nested = index(&[(&list, None)]);
+
+        let mut source = Memory::default()
+            .with_blob(&amd64)
+            .with_blob(&arm64)
+            .with_manifest("x86", Manifest::DOCKER_V2, None, &x86)
+            .with_manifest("list", Manifest::OCI_INDEX, None, &list)
+            .with_manifest("nested", Manifest::OCI_INDEX, None, &nested);
+        for (media_type, manifest) in [
+            (Manifest::DOCKER_V2, &x86),
+            (Manifest::DOCKER_V2, &arm),
+            (Manifest::OCI_INDEX, &list),
+        ] {
+            let digest = Digest::sha256(manifest).to_string();
+            source = source.with_manifest(&digest, media_type, None, manifest);
+        }
+
+        let source = Arc::new(source);
+        let open = |reference: &str, platform: &str| {
+            let platform = platform.parse().unwrap();
+            let image = Image::new(source.clone(), reference, &platform)?;
+            anyhow::Result::<String>::Ok(image.digest().to_string())
+        };
+
+        // A list resolves to the image for the platform...
+        let x86 = Digest::sha256(&x86).to_string();
+        let arm = Digest::sha256(&arm).to_string();
+        assert_eq!(open("list", "linux/amd64").unwrap(), x86);
+        assert_eq!(open("list", "linux/arm64").unwrap(), arm);
+        assert!(open("list", "linux/s390x").is_err());
+
+        // ...even when it is nested in another, which doesn't say what it
+        // covers.
+        assert_eq!(open("nested", "linux/arm64").unwrap(), arm);
+
+        // An image, though, is only accepted for its own platform.
+        assert_eq!(open("x86", "linux/amd64").unwrap(), x86);
+        let err = open("x86", "linux/arm64").unwrap_err();
+        assert!(err.to_string().contains("image is for linux/amd64"));
     }
 }
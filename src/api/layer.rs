@@ -1,64 +1,524 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Repository;
+use super::agent;
+use super::repository::ranged;
+use super::retry::retry;
+use super::Source;
 use crate::formats::docker::v2::Layer as Level;
-use crate::iotools::{Either, Validator};
+use crate::formats::Digest;
+use crate::iotools::{Either, Validatable, Validator};
 
-use std::io::Read;
+use std::fmt::Display;
+use std::io::{BufRead, Chain, Cursor, Error, ErrorKind, Read, Write};
+use std::sync::Arc;
 
 use anyhow::{anyhow, Result};
+use bzip2::bufread::BzDecoder;
 use flate2::read::GzDecoder;
+use log::warn;
+use ureq::Agent;
+use url::Url;
+use xz2::bufread::XzDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+/// A reader that decompresses a layer
+type Decompressor<R> = Either<
+    GzDecoder<R>,
+    Either<ZstdDecoder<'static, R>, Either<XzDecoder<R>, Either<BzDecoder<R>, R>>>,
+>;
+
+/// The compression applied to a layer
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Comp {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    None,
+}
+
+impl Comp {
+    /// The length of the longest magic number that we look for
+    const MAGIC: usize = 6;
+
+    /// Detects the compression from the magic bytes at the start of `data`
+    ///
+    /// Tar has no magic at the start, so anything unrecognized is taken to
+    /// be uncompressed.
+    fn sniff(data: &[u8]) -> Self {
+        match data {
+            [0x1f, 0x8b, ..] => Comp::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Comp::Zstd,
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00, ..] => Comp::Xz,
+            [b'B', b'Z', b'h', ..] => Comp::Bzip2,
+            _ => Comp::None,
+        }
+    }
+}
+
+impl Display for Comp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Comp::Gzip => "gzip",
+            Comp::Zstd => "zstd",
+            Comp::Xz => "xz",
+            Comp::Bzip2 => "bzip2",
+            Comp::None => "uncompressed",
+        })
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Layer {
-    repo: Repository,
+    source: Arc<dyn Source>,
     level: Level,
+    diff_id: Option<Digest>,
 }
 
 impl Layer {
-    pub(super) fn new(repo: Repository, level: Level) -> Self {
-        Self { repo, level }
-    }
-
-    pub fn decompressor<R: Read>(&self, reader: R) -> Result<Either<GzDecoder<R>, R>> {
-        enum Comp {
-            Gzip,
-            None,
+    /// Creates a layer, which is checked against `diff_id` once uncompressed
+    pub(super) fn new(source: Arc<dyn Source>, level: Level, diff_id: Option<Digest>) -> Self {
+        Self {
+            source,
+            level,
+            diff_id,
         }
+    }
 
-        let comp = match self.level.media_type.as_deref() {
-            Some("application/vnd.docker.image.rootfs.diff.tar.gzip") => Comp::Gzip,
-            Some("application/vnd.docker.image.rootfs.diff.tar") => Comp::None,
+    pub fn decompressor<R: BufRead>(
+        &self,
+        mut reader: R,
+    ) -> Result<Decompressor<Chain<Cursor<Vec<u8>>, R>>> {
+        let declared = match self.level.media_type.as_deref() {
+            Some("application/vnd.docker.image.rootfs.diff.tar.gzip") => Some(Comp::Gzip),
+            Some("application/vnd.docker.image.rootfs.diff.tar.zstd") => Some(Comp::Zstd),
+            Some("application/vnd.docker.image.rootfs.diff.tar") => Some(Comp::None),
 
-            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip") => Comp::Gzip,
-            Some("application/vnd.oci.image.layer.nondistributable.v1.tar") => Comp::None,
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+gzip") => {
+                Some(Comp::Gzip)
+            }
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar+zstd") => {
+                Some(Comp::Zstd)
+            }
+            Some("application/vnd.oci.image.layer.nondistributable.v1.tar") => Some(Comp::None),
 
-            Some("application/vnd.oci.image.layer.v1.tar+gzip") => Comp::Gzip,
-            Some("application/vnd.oci.image.layer.v1.tar") => Comp::None,
+            Some("application/vnd.oci.image.layer.v1.tar+gzip") => Some(Comp::Gzip),
+            Some("application/vnd.oci.image.layer.v1.tar+zstd") => Some(Comp::Zstd),
+            Some("application/vnd.oci.image.layer.v1.tar") => Some(Comp::None),
 
-            None => Comp::None,
+            None => None,
             kind => return Err(anyhow!("unkown layer type: {:?}", kind)),
         };
 
+        // Without a media type, go by the data. With one, warn if the data
+        // disagrees, since tar would otherwise fail with a confusing error.
+        // A single read may return less than the magic number, so read until
+        // we have all of it or the layer ends.
+        let mut magic = Vec::with_capacity(Comp::MAGIC);
+        (&mut reader)
+            .take(Comp::MAGIC as u64)
+            .read_to_end(&mut magic)?;
+        let sniffed = Comp::sniff(&magic);
+        let reader = Cursor::new(magic).chain(reader);
+        if let Some(declared) = declared.filter(|d| *d != sniffed) {
+            warn!(
+                "{}: declared as {} but looks like {}",
+                self.level.digest, declared, sniffed
+            );
+        }
+
+        let comp = declared.unwrap_or(sniffed);
+
         let x = match comp {
             Comp::Gzip => Either::One(GzDecoder::new(reader)),
-            Comp::None => Either::Two(reader),
+            Comp::Zstd => Either::Two(Either::One(ZstdDecoder::with_buffer(reader)?)),
+            Comp::Xz => Either::Two(Either::Two(Either::One(XzDecoder::new(reader)))),
+            Comp::Bzip2 => Either::Two(Either::Two(Either::Two(Either::One(BzDecoder::new(
+                reader,
+            ))))),
+            Comp::None => Either::Two(Either::Two(Either::Two(Either::Two(reader)))),
         };
 
         Ok(x)
     }
 
+    /// Decompresses the layer, validating the result against its diff_id
+    ///
+    /// As with the download, a mismatch is reported at the end of the stream.
+    pub fn uncompressed<R: BufRead>(&self, reader: R) -> Result<impl Read> {
+        let reader = self.decompressor(reader)?;
+
+        Ok(match &self.diff_id {
+            Some(diff_id) => Either::One(DiffId {
+                reader,
+                digest: diff_id.clone(),
+            }),
+            None => Either::Two(reader),
+        })
+    }
+
+    /// The digest of the layer's blob
+    pub fn digest(&self) -> &Digest {
+        &self.level.digest
+    }
+
+    /// The places to download the layer from, in order of preference
+    ///
+    /// The source comes first, followed by any `urls` from the descriptor.
+    /// These are how foreign (nondistributable) layers are distributed.
+    fn origins(&self) -> Result<Vec<Origin>> {
+        let mut origins = vec![Origin::Source(self.source.clone())];
+
+        for url in self.level.urls.iter() {
+            let parsed = match Url::parse(url) {
+                Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+                _ => {
+                    warn!("{}: ignoring unsupported URL {}", self.level.digest, url);
+                    continue;
+                }
+            };
+
+            let agent = agent::foreign(&parsed)?;
+            origins.push(Origin::Url(agent, url.clone()));
+        }
+
+        Ok(origins)
+    }
+
     pub fn download(&self) -> Result<(u64, impl Read + Send)> {
-        let path = format!("blobs/{}", self.level.digest);
+        let digest = &self.level.digest;
+        let mut opened = None;
+        let mut error = None;
 
-        let rep = self.repo.get(&path, &[])?;
-        let len = rep
-            .header("Content-Length")
-            .and_then(|s| s.parse().ok())
-            .unwrap_or(self.level.size);
+        for origin in self.origins()? {
+            if let Some(e) = error.take() {
+                warn!("{}: falling through to {}: {:#}", digest, origin, e);
+            }
 
-        let validator = Validator::new(rep.into_reader(), self.level.digest.clone());
+            match origin.open(digest) {
+                Ok((len, reader)) => {
+                    opened = Some((origin, len, reader));
+                    break;
+                }
+
+                Err(e) => error = Some(e),
+            }
+        }
+
+        let (origin, len, reader) = match (opened, error) {
+            (Some(opened), ..) => opened,
+            (None, Some(e)) => return Err(e),
+            (None, None) => return Err(anyhow!("{}: nowhere to download from", digest)),
+        };
+
+        let len = len.unwrap_or(self.level.size);
+
+        // The validator sits above the resumption, so that it sees each
+        // byte exactly once however many times the download is resumed.
+        let resumable = Resumable {
+            origin,
+            digest: digest.clone(),
+            len: Some(len).filter(|len| *len > 0),
+            offset: 0,
+            failures: 0,
+            reader,
+        };
+
+        let validator = Validator::new(resumable, digest.clone());
         Ok((len, validator))
     }
 }
+
+/// A place that the blob of a layer can be downloaded from
+enum Origin {
+    /// The source of the image
+    Source(Arc<dyn Source>),
+
+    /// A URL from the layer descriptor
+    ///
+    /// These are usually third-party hosts, so they get their own agent and
+    /// never see the credentials for the registry.
+    Url(Agent, String),
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Source(source) => write!(f, "{}", source),
+            Origin::Url(.., url) => f.write_str(url),
+        }
+    }
+}
+
+impl Origin {
+    fn open(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+        match self {
+            Origin::Source(source) => source.blob(digest),
+            Origin::Url(agent, url) => {
+                let rep = retry(url, || agent::request(agent, "GET", url, &[]))?;
+                let len = rep.header("Content-Length").and_then(|s| s.parse().ok());
+                Ok((len, Box::new(rep.into_reader())))
+            }
+        }
+    }
+
+    fn open_at(&self, digest: &Digest, offset: u64) -> Result<Box<dyn Read + Send>> {
+        match self {
+            Origin::Source(source) => source.blob_at(digest, offset),
+            Origin::Url(agent, url) => {
+                let range = format!("bytes={}-", offset);
+                let headers = [("Range", range.as_str())];
+                let rep = retry(url, || agent::request(agent, "GET", url, &headers))?;
+                ranged(url, rep, offset)
+            }
+        }
+    }
+}
+
+/// A decompressed layer that is checked against its diff_id at the end
+///
+/// This works like a `Validator`, but the error names both the diff_id that
+/// the layer declares and the one that it actually has.
+struct DiffId<R: Read> {
+    reader: R,
+    digest: Digest,
+}
+
+impl<R: Read> Read for DiffId<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.reader.read(buf)?;
+        self.digest.write_all(&buf[..size])?;
+        if size == 0 && !buf.is_empty() && !self.digest.validate() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "diff_id {} does not match the expected {}",
+                    self.digest.computed(),
+                    self.digest
+                ),
+            ));
+        }
+
+        Ok(size)
+    }
+}
+
+/// A blob download that resumes where it left off when interrupted
+struct Resumable {
+    origin: Origin,
+    digest: Digest,
+    len: Option<u64>,
+    offset: u64,
+    failures: usize,
+    reader: Box<dyn Read + Send>,
+}
+
+impl Resumable {
+    /// Give up after this many failures in a row without progress
+    const MAX_FAILURES: usize = 5;
+}
+
+impl Read for Resumable {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let err = match self.reader.read(buf) {
+                // The connection was closed before the end of the blob.
+                Ok(0) if !buf.is_empty() && self.len.is_some_and(|len| self.offset < len) => {
+                    Error::from(ErrorKind::UnexpectedEof)
+                }
+
+                Ok(n) => {
+                    self.offset += n as u64;
+                    if n > 0 {
+                        self.failures = 0;
+                    }
+
+                    return Ok(n);
+                }
+
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => e,
+            };
+
+            self.failures += 1;
+            if self.failures > Self::MAX_FAILURES {
+                return Err(err);
+            }
+
+            warn!("{}: resuming at byte {}: {}", self.digest, self.offset, err);
+            self.reader = self
+                .origin
+                .open_at(&self.digest, self.offset)
+                .map_err(|e| Error::other(format!("{:#}", e)))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Comp, Layer, Level};
+    use crate::api::agent::test::{reply, Server};
+    use crate::api::source::test::Memory;
+    use crate::formats::Digest;
+
+    use std::io::Read;
+    use std::sync::Arc;
+
+    const PAYLOAD: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    /// The descriptor of `blob` with the given media type
+    fn level(media_type: Option<&str>, blob: &[u8]) -> Level {
+        Level {
+            media_type: media_type.map(Into::into),
+            size: blob.len() as u64,
+            digest: Digest::sha256(blob),
+            urls: Vec::new(),
+        }
+    }
+
+    /// Decompresses `blob` as a layer with the given media type
+    fn decompress(media_type: Option<&str>, blob: &[u8]) -> std::io::Result<Vec<u8>> {
+        let source = Arc::new(Memory::default());
+        let layer = Layer::new(source, level(media_type, blob), None);
+        let mut out = Vec::new();
+        let mut reader = layer.decompressor(blob).unwrap();
+        reader.read_to_end(&mut out).map(|_| out)
+    }
+
+    #[test]
+    fn resume() {
+        let source = Arc::new(Memory::default().with_blob(PAYLOAD).truncated());
+        let layer = Layer::new(source, level(None, PAYLOAD), None);
+        let (len, mut reader) = layer.download().unwrap();
+        assert_eq!(len, PAYLOAD.len() as u64);
+
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, PAYLOAD);
+    }
+
+    #[test]
+    fn urls() {
+        let server = Server::new();
+        let host = server.host();
+        let server = server.serve(vec![reply("200 OK", &[], PAYLOAD)]);
+
+        // The source lacks the blob, the unsupported URL is skipped and the
+        // last one works.
+        let level = Level {
+            urls: vec![
+                format!("ftp://{}/blob", host),
+                format!("http://{}/blob", host),
+            ],
+            ..level(None, PAYLOAD)
+        };
+
+        let layer = Layer::new(Arc::new(Memory::default()), level, None);
+        let (len, mut reader) = layer.download().unwrap();
+        assert_eq!(len, PAYLOAD.len() as u64);
+
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, PAYLOAD);
+
+        let requests = server.join().unwrap();
+        let request = requests[0].to_ascii_lowercase();
+        assert!(request.starts_with("get /blob "));
+        assert!(!request.contains("authorization"));
+    }
+
+    #[test]
+    fn uncompressed() {
+        let tar = Some("application/vnd.oci.image.layer.v1.tar");
+        let read = |diff_id| {
+            let source = Arc::new(Memory::default());
+            let layer = Layer::new(source, level(tar, PAYLOAD), Some(diff_id));
+            let mut out = Vec::new();
+            layer
+                .uncompressed(PAYLOAD)
+                .unwrap()
+                .read_to_end(&mut out)
+                .map(|_| out)
+        };
+
+        assert_eq!(read(Digest::sha256(PAYLOAD)).unwrap(), PAYLOAD);
+
+        let expected = Digest::sha256(b"something else");
+        let err = read(expected.clone()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "diff_id {} does not match the expected {}",
+                Digest::sha256(PAYLOAD),
+                expected
+            )
+        );
+    }
+
+    #[test]
+    fn zstd() {
+        let compressed = zstd::encode_all(PAYLOAD, 0).unwrap();
+        let media_type = Some("application/vnd.oci.image.layer.v1.tar+zstd");
+        assert_eq!(decompress(media_type, &compressed).unwrap(), PAYLOAD);
+    }
+
+    #[test]
+    fn sniff() {
+        use std::io::Write;
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gzip.write_all(PAYLOAD).unwrap();
+        let gzip = gzip.finish().unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(PAYLOAD).unwrap();
+        let mut bzip2 = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+        bzip2.write_all(PAYLOAD).unwrap();
+
+        let cases = [
+            (Comp::Gzip, gzip.clone()),
+            (Comp::Zstd, zstd::encode_all(PAYLOAD, 0).unwrap()),
+            (Comp::Xz, xz.finish().unwrap()),
+            (Comp::Bzip2, bzip2.finish().unwrap()),
+            (Comp::None, PAYLOAD.to_vec()),
+        ];
+
+        // Without a media type, the data decides.
+        for (comp, compressed) in cases.iter() {
+            assert_eq!(Comp::sniff(compressed), *comp);
+            assert_eq!(decompress(None, compressed).unwrap(), PAYLOAD);
+        }
+
+        // With one, the media type wins even when the data disagrees.
+        let tar = Some("application/vnd.oci.image.layer.v1.tar");
+        assert_eq!(decompress(tar, &gzip).unwrap(), gzip);
+
+        let tgz = Some("application/vnd.oci.image.layer.v1.tar+gzip");
+        assert!(decompress(tgz, PAYLOAD).is_err());
+    }
+
+    #[test]
+    fn trickle() {
+        use std::io::{BufReader, Write};
+
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(PAYLOAD).unwrap();
+        let xz = xz.finish().unwrap();
+
+        // The magic number is sniffed even if it arrives a byte at a time.
+        let layer = Layer::new(Arc::new(Memory::default()), level(None, &xz), None);
+        let mut out = Vec::new();
+        let reader = BufReader::with_capacity(1, &xz[..]);
+        let mut reader = layer.decompressor(reader).unwrap();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, PAYLOAD);
+
+        // Layers shorter than any magic number are just passed through.
+        let reader = BufReader::with_capacity(1, &b"ab"[..]);
+        let mut reader = layer.decompressor(reader).unwrap();
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"ab");
+    }
+}
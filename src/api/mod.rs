@@ -1,10 +1,21 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+mod agent;
+mod auth;
+mod credentials;
+mod endpoint;
 mod image;
 mod layer;
+mod platform;
+mod registries;
 mod repository;
+mod retry;
+mod source;
 
+pub use self::credentials::Credentials;
 pub use self::image::Image;
 pub use self::layer::Layer;
+pub use self::platform::Platform;
 pub use self::repository::Repository;
+pub use self::source::{open, Source};
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+
+/// The platform to select from a manifest list or image index
+///
+/// The fields are normalized to the names used in image manifests (i.e.
+/// `amd64` rather than `x86_64`), so that they can be compared directly.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    os: String,
+    architecture: String,
+    variant: Option<String>,
+}
+
+impl Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = Error;
+
+    /// Parses a platform in the form `os/arch[/variant]`
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split('/').collect::<Vec<_>>()[..] {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => Ok(Self::new(os, arch, None)),
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => {
+                Ok(Self::new(os, arch, Some(variant)))
+            }
+            _ => Err(anyhow!(
+                "invalid platform: {:?} (expected os/arch[/variant])",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for Platform {
+    /// The platform of the machine we are running on
+    fn default() -> Self {
+        Self::new(std::env::consts::OS, std::env::consts::ARCH, None)
+    }
+}
+
+impl Platform {
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Self {
+        let (architecture, default) = match architecture {
+            "x86_64" | "x86-64" => ("amd64", None),
+            "i386" | "i486" | "i586" | "i686" => ("386", None),
+            "aarch64" | "arm64" => ("arm64", Some("v8")),
+            "armhf" | "armv7l" => ("arm", Some("v7")),
+            "armel" => ("arm", Some("v6")),
+            "arm" => ("arm", Some("v7")),
+            arch => (arch, None),
+        };
+
+        Self {
+            os: os.to_ascii_lowercase(),
+            architecture: architecture.into(),
+            variant: variant.or(default).map(String::from),
+        }
+    }
+
+    /// Whether an image for the `other` platform can run on this platform
+    ///
+    /// If we have no variant, images for any variant match.
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == other.architecture
+            && (self.variant.is_none() || self.variant == other.variant)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Platform;
+
+    #[test]
+    fn matches() {
+        let amd64: Platform = "linux/x86_64".parse().unwrap();
+        assert_eq!(amd64.to_string(), "linux/amd64");
+        assert!(amd64.matches(&Platform::new("linux", "amd64", None)));
+        assert!(!amd64.matches(&Platform::new("windows", "amd64", None)));
+        assert!(!amd64.matches(&Platform::new("linux", "arm64", None)));
+
+        let arm64: Platform = "linux/aarch64".parse().unwrap();
+        assert_eq!(arm64.to_string(), "linux/arm64/v8");
+        assert!(arm64.matches(&Platform::new("linux", "arm64", None)));
+        assert!(arm64.matches(&Platform::new("linux", "arm64", Some("v8"))));
+        assert!(!arm64.matches(&Platform::new("linux", "arm", Some("v7"))));
+
+        let armv6: Platform = "linux/arm/v6".parse().unwrap();
+        assert!(armv6.matches(&Platform::new("linux", "arm", Some("v6"))));
+        assert!(!armv6.matches(&Platform::new("linux", "arm", None)));
+
+        assert!("linux".parse::<Platform>().is_err());
+        assert!("linux//v8".parse::<Platform>().is_err());
+        assert!("linux/arm/v7/x".parse::<Platform>().is_err());
+    }
+}
@@ -0,0 +1,578 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Context, Result};
+use serde::Deserialize;
+
+/// The places to look for the configuration file `name`, in order of preference
+///
+/// Our own configuration comes before that of the containers tools and, for
+/// each, the user's configuration comes before the system's.
+pub fn config_paths(name: &str) -> Vec<PathBuf> {
+    let config = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    };
+
+    let mut paths = Vec::new();
+    for dir in ["wyrcan", "containers"] {
+        if let Some(config) = &config {
+            paths.push(config.join(dir).join(name));
+        }
+
+        paths.push(Path::new("/etc").join(dir).join(name));
+    }
+
+    paths
+}
+
+/// A registry location and the settings for talking to it
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Location {
+    /// The registry host and, optionally, a repository namespace
+    #[serde(default)]
+    pub location: String,
+
+    /// Skip TLS verification and fall back to plain HTTP
+    #[serde(default)]
+    pub insecure: bool,
+
+    /// Only ever use plain HTTP
+    #[serde(default)]
+    pub plain_http: bool,
+
+    /// Verify the registry's TLS certificate
+    #[serde(default = "Location::yes")]
+    pub tls_verify: bool,
+}
+
+impl Location {
+    fn yes() -> bool {
+        true
+    }
+
+    /// The URL schemes to try, in order
+    pub fn schemes(&self) -> &'static [&'static str] {
+        match (self.plain_http, self.insecure) {
+            (true, ..) => &["http"],
+            (false, true) => &["https", "http"],
+            (false, false) => &["https"],
+        }
+    }
+
+    /// Whether TLS certificates should be verified
+    pub fn verify(&self) -> bool {
+        self.tls_verify && !self.insecure
+    }
+
+    /// This location with the given name appended to it
+    fn join(&self, rest: &str) -> Self {
+        Self {
+            location: format!("{}{}", self.location, rest),
+            ..self.clone()
+        }
+    }
+}
+
+impl From<String> for Location {
+    fn from(location: String) -> Self {
+        Self {
+            location,
+            insecure: false,
+            plain_http: false,
+            tls_verify: true,
+        }
+    }
+}
+
+/// The settings for a registry from a `[[registry]]` table
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct Registry {
+    /// The images this entry applies to (defaults to `location`)
+    #[serde(default)]
+    prefix: Option<String>,
+
+    #[serde(flatten)]
+    location: Location,
+
+    /// The mirrors to try, in order, before the location itself
+    #[serde(default)]
+    mirror: Vec<Location>,
+
+    /// Only use the mirrors for content that is pulled by digest
+    #[serde(default)]
+    mirror_by_digest_only: bool,
+
+    /// Refuse to pull any images under the prefix
+    #[serde(default)]
+    blocked: bool,
+}
+
+impl Registry {
+    fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or(&self.location.location)
+    }
+
+    /// Whether `name` (i.e. `host/path`) falls under `prefix`
+    ///
+    /// The prefix matches at component boundaries. A leading `*.` matches
+    /// any subdomain.
+    fn matches(prefix: &str, name: &str) -> bool {
+        if let Some(domain) = prefix.strip_prefix("*.") {
+            let host = name.split('/').next().unwrap_or_default();
+            return host.ends_with(&format!(".{}", domain));
+        }
+
+        match name.strip_prefix(prefix) {
+            Some(rest) => rest.is_empty() || rest.starts_with(['/', ':', '@']),
+            None => false,
+        }
+    }
+}
+
+/// How short names (i.e. names without a registry) are resolved
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ShortNameMode {
+    /// Reject short names that could resolve to more than one registry
+    Enforcing,
+
+    /// Try each of the unqualified-search registries in order
+    #[default]
+    Permissive,
+
+    /// Like `Permissive`, but ignore the short-name aliases
+    Disabled,
+}
+
+/// Registry configuration in the style of `registries.conf`
+///
+/// We use the TOML format of `containers-registries.conf(5)`, with the
+/// `plain-http` and `tls-verify` extensions for registries.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Registries {
+    #[serde(default)]
+    unqualified_search_registries: Option<Vec<String>>,
+
+    #[serde(default)]
+    short_name_mode: Option<ShortNameMode>,
+
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+
+    #[serde(default)]
+    registry: Vec<Registry>,
+}
+
+impl Registries {
+    /// The configuration files to search, in order of preference
+    fn paths() -> Vec<PathBuf> {
+        let mut paths = Vec::new();
+
+        for var in ["WYRCAN_REGISTRIES_CONF", "CONTAINERS_REGISTRIES_CONF"] {
+            if let Some(path) = std::env::var_os(var) {
+                paths.push(path.into());
+            }
+        }
+
+        paths.extend(config_paths("registries.conf"));
+        paths
+    }
+
+    /// Applies the drop-in configuration `other` on top of this one
+    ///
+    /// As with the containers tools, settings in the drop-in replace ours,
+    /// its aliases are added to ours and its registries replace any of ours
+    /// with the same prefix.
+    fn merge(&mut self, other: Self) {
+        if other.unqualified_search_registries.is_some() {
+            self.unqualified_search_registries = other.unqualified_search_registries;
+        }
+
+        if other.short_name_mode.is_some() {
+            self.short_name_mode = other.short_name_mode;
+        }
+
+        self.aliases.extend(other.aliases);
+
+        for registry in other.registry {
+            self.registry.retain(|r| r.prefix() != registry.prefix());
+            self.registry.push(registry);
+        }
+    }
+
+    /// Reads the configuration file at `path`, if it exists
+    fn read(path: &Path) -> Result<Option<Self>> {
+        match std::fs::read_to_string(path) {
+            Ok(data) => Ok(Some(
+                toml::from_str(&data).with_context(|| format!("{:?}", path))?,
+            )),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("{:?}", path)),
+        }
+    }
+
+    /// Reads the configuration file at `path` along with its drop-ins
+    ///
+    /// The drop-ins are the `*.conf` files in the `<path>.d` directory, which
+    /// are applied in order of their names. Distributions ship short-name
+    /// aliases this way, e.g. in `registries.conf.d/000-shortnames.conf`.
+    fn open(path: &Path) -> Result<Option<Self>> {
+        let mut registries = match Self::read(path)? {
+            Some(registries) => registries,
+            None => return Ok(None),
+        };
+
+        let mut dir = path.as_os_str().to_owned();
+        dir.push(".d");
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(registries)),
+            Err(e) => return Err(e).with_context(|| format!("{:?}", dir)),
+        };
+
+        let mut paths = entries
+            .map(|e| e.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.retain(|p| p.extension().is_some_and(|e| e == "conf"));
+        paths.sort();
+
+        for path in paths {
+            if let Some(dropin) = Self::read(&path)? {
+                registries.merge(dropin);
+            }
+        }
+
+        Ok(Some(registries))
+    }
+
+    /// Loads the first configuration file found, with its drop-ins
+    ///
+    /// If there is none, the default configuration is used.
+    fn load() -> Result<Self> {
+        for path in Self::paths() {
+            if let Some(registries) = Self::open(&path)? {
+                return Ok(registries);
+            }
+        }
+
+        Ok(Self::default())
+    }
+
+    /// The configuration, loaded on first use
+    pub fn global() -> Result<&'static Self> {
+        static REGISTRIES: OnceLock<Registries> = OnceLock::new();
+
+        if let Some(registries) = REGISTRIES.get() {
+            return Ok(registries);
+        }
+
+        let registries = Self::load()?;
+        Ok(REGISTRIES.get_or_init(|| registries))
+    }
+
+    /// The fully-qualified names that the short name `name` may refer to
+    ///
+    /// An alias wins outright. Otherwise, `name` is qualified with each of
+    /// the unqualified-search registries, in order, or with `default` if
+    /// there are none. In enforcing mode, a name that could refer to more
+    /// than one image is an error.
+    pub fn candidates(&self, name: &str, default: &str) -> Result<Vec<String>> {
+        let mode = self.short_name_mode.unwrap_or_default();
+        let search = self
+            .unqualified_search_registries
+            .as_deref()
+            .unwrap_or_default();
+
+        if mode != ShortNameMode::Disabled {
+            if let Some(alias) = self.aliases.get(name) {
+                return Ok(vec![alias.clone()]);
+            }
+        }
+
+        let candidates: Vec<_> = match search.is_empty() {
+            true => vec![format!("{}/{}", default, name)],
+            false => search
+                .iter()
+                .map(|registry| format!("{}/{}", registry, name))
+                .collect(),
+        };
+
+        if mode == ShortNameMode::Enforcing && candidates.len() > 1 {
+            return Err(anyhow!(
+                "ambiguous short name {:?} could be any of: {}; use a fully-qualified name or add an alias",
+                name,
+                candidates.join(", ")
+            ));
+        }
+
+        Ok(candidates)
+    }
+
+    /// Resolves the repository at `host/path` to the locations to pull from
+    ///
+    /// The entry with the longest matching prefix wins. Its prefix is
+    /// rewritten to its location and to each of its mirrors. The mirrors come
+    /// first, in order, followed by the location itself. Each location is
+    /// paired with whether it may only be used to pull by digest.
+    pub fn resolve(&self, host: &str, path: &str) -> Result<Vec<(Location, bool)>> {
+        let name = format!("{}/{}", host, path);
+
+        let registry = self
+            .registry
+            .iter()
+            .filter(|r| Registry::matches(r.prefix(), &name))
+            .max_by_key(|r| r.prefix().len());
+
+        let registry = match registry {
+            Some(registry) if registry.blocked => return Err(anyhow!("{}: blocked", name)),
+            Some(registry) => registry,
+            None => return Ok(vec![(name.into(), false)]),
+        };
+
+        // Wildcard prefixes can't be rewritten, so they keep the name.
+        let wildcard = registry.prefix().starts_with("*.");
+        let rest = match wildcard {
+            true => &*name,
+            false => &name[registry.prefix().len()..],
+        };
+
+        let mut locations: Vec<_> = registry
+            .mirror
+            .iter()
+            .map(|m| (m.join(rest), registry.mirror_by_digest_only))
+            .collect();
+
+        let location = match wildcard || registry.location.location.is_empty() {
+            true => Location {
+                location: name.clone(),
+                ..registry.location.clone()
+            },
+            false => registry.location.join(rest),
+        };
+
+        locations.push((location, false));
+        Ok(locations)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Registries;
+
+    #[test]
+    fn resolve() {
+        let registries: Registries = toml::from_str(
+            r#"
+            [[registry]]
+            location = "localhost:5000"
+            insecure = true
+
+            [[registry]]
+            location = "lab.corp"
+            tls-verify = false
+
+            [[registry]]
+            prefix = "lab.corp/ci"
+            location = "lab.corp/ci"
+            plain-http = true
+
+            [[registry]]
+            prefix = "*.internal"
+            plain-http = true
+
+            [[registry]]
+            prefix = "docker.io"
+            location = "docker.io"
+
+            [[registry.mirror]]
+            location = "mirror.corp/hub"
+            insecure = true
+
+            [[registry.mirror]]
+            location = "backup.corp/hub"
+
+            [[registry]]
+            prefix = "quay.io/foo"
+            location = "registry.corp/foo"
+            mirror-by-digest-only = true
+
+            [[registry.mirror]]
+            location = "cache.corp/foo"
+
+            [[registry]]
+            prefix = "evil.io"
+            blocked = true
+            "#,
+        )
+        .unwrap();
+
+        let resolve = |host, path| -> Vec<_> {
+            let locations = registries.resolve(host, path).unwrap();
+            locations
+                .into_iter()
+                .map(|(l, d)| (l.schemes(), l.verify(), l.location, d))
+                .collect()
+        };
+
+        let https: &[_] = &["https"];
+        let http: &[_] = &["http"];
+        let both: &[_] = &["https", "http"];
+
+        assert_eq!(
+            resolve("localhost:5000", "boot/fedora"),
+            vec![(both, false, "localhost:5000/boot/fedora".into(), false)]
+        );
+
+        assert_eq!(
+            resolve("lab.corp", "boot/fedora"),
+            vec![(https, false, "lab.corp/boot/fedora".into(), false)]
+        );
+
+        assert_eq!(
+            resolve("lab.corp", "ci/fedora"),
+            vec![(http, true, "lab.corp/ci/fedora".into(), false)]
+        );
+
+        assert_eq!(
+            resolve("lab.corporate", "fedora"),
+            vec![(https, true, "lab.corporate/fedora".into(), false)]
+        );
+
+        assert_eq!(
+            resolve("mirror.internal", "fedora"),
+            vec![(http, true, "mirror.internal/fedora".into(), false)]
+        );
+
+        assert_eq!(
+            resolve("docker.io", "library/fedora"),
+            vec![
+                (both, false, "mirror.corp/hub/library/fedora".into(), false),
+                (https, true, "backup.corp/hub/library/fedora".into(), false),
+                (https, true, "docker.io/library/fedora".into(), false),
+            ]
+        );
+
+        assert_eq!(
+            resolve("quay.io", "foo/boot"),
+            vec![
+                (https, true, "cache.corp/foo/boot".into(), true),
+                (https, true, "registry.corp/foo/boot".into(), false),
+            ]
+        );
+
+        assert!(registries.resolve("evil.io", "boot").is_err());
+    }
+
+    #[test]
+    fn candidates() {
+        let registries: Registries = toml::from_str(
+            r#"
+            unqualified-search-registries = ["registry.corp", "quay.io"]
+
+            [aliases]
+            "boot" = "registry.corp/os/boot"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            registries.candidates("boot", "docker.io").unwrap(),
+            vec!["registry.corp/os/boot"]
+        );
+
+        assert_eq!(
+            registries.candidates("fedora", "docker.io").unwrap(),
+            vec!["registry.corp/fedora", "quay.io/fedora"]
+        );
+
+        let enforcing: Registries = toml::from_str(
+            r#"
+            unqualified-search-registries = ["registry.corp", "quay.io"]
+            short-name-mode = "enforcing"
+
+            [aliases]
+            "boot" = "registry.corp/os/boot"
+            "#,
+        )
+        .unwrap();
+
+        assert!(enforcing.candidates("fedora", "docker.io").is_err());
+        assert_eq!(
+            enforcing.candidates("boot", "docker.io").unwrap(),
+            vec!["registry.corp/os/boot"]
+        );
+
+        let default = Registries::default();
+        assert_eq!(
+            default.candidates("fedora", "docker.io").unwrap(),
+            vec!["docker.io/fedora"]
+        );
+    }
+
+    #[test]
+    fn dropins() {
+        let dir = std::env::temp_dir().join(format!("wyrcan-registries-{}", std::process::id()));
+        let path = dir.join("registries.conf");
+        std::fs::create_dir_all(dir.join("registries.conf.d")).unwrap();
+
+        // As shipped by Fedora
+        std::fs::write(
+            &path,
+            r#"
+            unqualified-search-registries = ["registry.fedoraproject.org", "registry.access.redhat.com", "docker.io"]
+            short-name-mode = "enforcing"
+
+            [[registry]]
+            location = "localhost:5000"
+            "#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            dir.join("registries.conf.d/000-shortnames.conf"),
+            r#"
+            [aliases]
+            "fedora" = "registry.fedoraproject.org/fedora"
+            "#,
+        )
+        .unwrap();
+
+        std::fs::write(
+            dir.join("registries.conf.d/50-local.conf"),
+            r#"
+            [[registry]]
+            location = "localhost:5000"
+            insecure = true
+            "#,
+        )
+        .unwrap();
+
+        std::fs::write(dir.join("registries.conf.d/99-ignored.conf.bak"), "garbage").unwrap();
+
+        let registries = Registries::open(&path).unwrap().unwrap();
+        assert_eq!(
+            registries.candidates("fedora", "docker.io").unwrap(),
+            vec!["registry.fedoraproject.org/fedora"]
+        );
+        assert!(registries.candidates("ubi", "docker.io").is_err());
+
+        let locations = registries.resolve("localhost:5000", "boot").unwrap();
+        assert_eq!(locations.len(), 1);
+        assert_eq!(locations[0].0.schemes(), ["https", "http"]);
+
+        assert!(Registries::open(&dir.join("missing.conf"))
+            .unwrap()
+            .is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,152 +1,346 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use super::Image;
+use super::endpoint::Endpoint;
+use super::registries::Registries;
+use super::source::{skip, Fetched, Source, Tags};
+use crate::formats::{Digest, Manifest, Reference};
 
-use std::cmp::max;
-use std::collections::HashMap;
 use std::fmt::Display;
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
-use anyhow::Result;
-use regex::Regex;
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
 use serde::Deserialize;
 use ureq::Response;
+use url::form_urlencoded;
 
+/// A repository and the endpoints that it can be pulled from
+///
+/// The endpoints come from the registries configuration: any mirrors, in
+/// order, followed by the (possibly rewritten) repository itself.
 #[derive(Clone, Debug)]
 pub struct Repository {
     host: String,
     path: String,
+    endpoints: Vec<Endpoint>,
+    used: Arc<AtomicUsize>,
 }
 
 impl Display for Repository {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut host = &*self.host;
-        for (into, from) in Self::ALIASES {
-            if &*self.host == *from && from.len() > into.len() {
-                host = *into;
-                break;
-            }
-        }
-
-        write!(f, "{}/{}", host, self.path)
+        write!(f, "{}/{}", self.host, self.path)
     }
 }
 
 impl Repository {
-    fn auth(&self, wwwauth: &str) -> Result<String> {
-        #[derive(Deserialize)]
-        struct Auth {
-            token: String,
-        }
+    /// Sends a `method` request to the first endpoint that answers it
+    ///
+    /// If an endpoint fails, we fall through to the next one. The error from
+    /// the last endpoint is returned if all of them fail.
+    fn request(&self, method: &str, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        let mut result = None;
 
-        const RE: &str = "([a-z]+)=\"([^\"]+)\"";
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            if !endpoint.serves(path) {
+                continue;
+            }
 
-        let mut map = HashMap::new();
-        let re = Regex::new(RE).unwrap();
-        for find in re.find_iter(wwwauth) {
-            for caps in re.captures_iter(find.as_str()) {
-                let k = caps.get(1).unwrap().as_str();
-                let v = caps.get(2).unwrap().as_str();
-                map.insert(k, v);
+            if let Some(Err(e)) = result.take() {
+                warn!("{}: falling through to {}: {:#}", self, endpoint, e);
             }
-        }
 
-        let base = map.remove("realm").unwrap();
-        let join: Vec<String> = map.iter().map(|(k, v)| [*k, *v].join("=")).collect();
-        let args = join.join("&");
-        let url = format!("{}?{}", base, args);
+            // Say which endpoint we use whenever that changes, unless it is
+            // just the repository itself from the start.
+            match endpoint.request(method, path, headers) {
+                Ok(rep) => {
+                    let last = self.used.swap(i, Ordering::Relaxed);
+                    if last != i && (last != usize::MAX || endpoint.location() != self.to_string())
+                    {
+                        info!("{}: using {}", self, endpoint);
+                    }
+
+                    return Ok(rep);
+                }
+
+                Err(e) => result = Some(Err(e)),
+            }
+        }
 
-        let auth: Auth = ureq::get(&url).call()?.into_json()?;
-        let token = format!("Bearer {}", auth.token);
-        Ok(token)
+        result.unwrap_or_else(|| Err(anyhow!("{}: no endpoint for {}", self, path)))
     }
 
+    /// Sends a GET request to the first endpoint that answers it
     pub(super) fn get(&self, path: &str, headers: &[(&str, &str)]) -> Result<Response> {
-        let url = format!("https://{}/v2/{}/{}", self.host, self.path, path);
+        self.request("GET", path, headers)
+    }
 
-        let mut auth = false;
-        let mut req = ureq::get(&url);
-        for (k, v) in headers {
-            req = req.set(k, v);
-            auth |= *k == "Authorization";
-        }
+    const DEFAULT_REGISTRY: &'static str = "docker.io";
+    const DEFAULT_PREFIX: &'static str = "library";
+    const DEFAULT_TAG: &'static str = "latest";
+
+    /// Opens the repository for an image reference
+    ///
+    /// Returns the repository along with the tag or digest to pull. If the
+    /// reference has both, the digest wins. When `listing` tags, a short name
+    /// resolves to the first repository found rather than the first image.
+    pub fn new(name: &str, listing: bool) -> Result<(Self, String)> {
+        let reference: Reference = name.parse()?;
+        let tag = match (&reference.digest, &reference.tag) {
+            (Some(digest), ..) => digest.to_string(),
+            (None, Some(tag)) => tag.clone(),
+            (None, None) => Self::DEFAULT_TAG.into(),
+        };
+
+        // Qualify short names
+        let registries = Registries::global()?;
+        let candidates = match &reference.registry {
+            Some(registry) => vec![format!("{}/{}", registry, reference.repository)],
+            None => registries.candidates(&reference.repository, Self::DEFAULT_REGISTRY)?,
+        };
+
+        let mut result = None;
+        for name in candidates.iter() {
+            // Aliases and search registries come from the configuration.
+            let candidate: Reference = name.parse().with_context(|| name.clone())?;
+            let host = candidate
+                .registry
+                .as_deref()
+                .ok_or_else(|| anyhow!("{}: not fully qualified", name))?;
+            let path = &*candidate.repository;
+
+            // Add the default prefix if necessary.
+            let path = match host == Self::DEFAULT_REGISTRY && !path.contains('/') {
+                true => format!("{}/{}", Self::DEFAULT_PREFIX, path),
+                false => path.into(),
+            };
+
+            let endpoints = registries
+                .resolve(host, &path)?
+                .iter()
+                .map(|(location, digest_only)| Endpoint::new(location, *digest_only))
+                .collect::<Result<_>>()?;
+
+            let repo = Self {
+                host: host.into(),
+                path,
+                endpoints,
+                used: Arc::new(AtomicUsize::new(usize::MAX)),
+            };
 
-        match req.call() {
-            Err(ureq::Error::Status(401, rep)) if !auth && rep.has("Www-Authenticate") => {
-                let token = self.auth(rep.header("Www-Authenticate").unwrap())?;
-                self.get(path, &[("Authorization", &token)])
+            // There is nothing to choose between for a single candidate.
+            if candidates.len() == 1 {
+                return Ok((repo, tag));
             }
 
-            Ok(rep) => Ok(rep),
-            Err(e) => Err(e.into()),
+            // Otherwise, the first candidate that has the image wins or, when
+            // listing tags, the first that has the repository. Only check for
+            // it, since the caller fetches whatever it needs afterwards.
+            if let Some(Err(e)) = result.take() {
+                warn!("{}: {:#}", name, e);
+            }
+
+            let found = match listing {
+                true => repo.get("tags/list?n=1", &[]),
+                false => {
+                    let accept = Manifest::MEDIA_TYPES.join(", ");
+                    let path = format!("manifests/{}", tag);
+                    repo.request("HEAD", &path, &[("Accept", &accept)])
+                }
+            };
+
+            match found {
+                Ok(..) => {
+                    info!("{}: resolved to {}", reference, repo);
+                    return Ok((repo, tag));
+                }
+
+                Err(e) => result = Some(Err(e)),
+            }
         }
+
+        result.unwrap_or_else(|| Err(anyhow!("{}: no candidates", reference)))
     }
+}
 
-    const DEFAULT_REGISTRY: &'static str = "docker.io";
-    const DEFAULT_PREFIX: &'static str = "library";
-    const DEFAULT_TAG: &'static str = "latest";
+/// The tags of a repository, fetched a page at a time
+struct Pages<'a> {
+    repo: &'a Repository,
+    next: Option<String>,
+    page: std::vec::IntoIter<String>,
+}
+
+impl Pages<'_> {
+    /// Finds the target of the `rel="next"` link in a `Link` header
+    fn link(header: &str) -> Option<&str> {
+        header.split(',').find_map(|link| {
+            let mut parts = link.split(';').map(str::trim);
+            let target = parts.next()?.strip_prefix('<')?.strip_suffix('>')?;
+
+            parts
+                .filter_map(|p| p.split_once('='))
+                .filter(|(k, ..)| k.trim().eq_ignore_ascii_case("rel"))
+                .flat_map(|(.., v)| v.trim().trim_matches('"').split_whitespace())
+                .any(|rel| rel.eq_ignore_ascii_case("next"))
+                .then_some(target)
+        })
+    }
 
-    const LOCALHOST: &'static str = "localhost";
-    const ALIASES: &'static [(&'static str, &'static str)] =
-        &[("docker.io", "registry.hub.docker.com")];
-
-    pub fn new(mut repository: &str) -> Result<(Self, &str)> {
-        // Remove any tag or digest
-        let sep = repository.rfind('/').unwrap_or_default();
-        let lbl = repository.rfind(':').unwrap_or_default();
-        let dig = repository.rfind('@').unwrap_or_default();
-        let mut tag = Self::DEFAULT_TAG;
-        if lbl > sep || dig > sep {
-            let (lhs, rhs) = repository.split_at(max(lbl, dig));
-            repository = lhs;
-            tag = &rhs[1..];
+    /// Fetches the page at `path` and returns its tags and the next page
+    ///
+    /// Only the query of the next link is kept, since the link is relative
+    /// to the endpoint that served the page, which may be a mirror.
+    fn fetch(&self, path: &str) -> Result<(Vec<String>, Option<String>)> {
+        #[derive(Debug, Deserialize)]
+        struct Page {
+            #[serde(default)]
+            tags: Option<Vec<String>>,
         }
 
-        // Extract the registry
-        let mut host = Self::DEFAULT_REGISTRY;
-        if let Some((lhs, rhs)) = repository.find('/').map(|n| repository.split_at(n)) {
-            if lhs.contains('.') || lhs.contains(':') || lhs == Self::LOCALHOST {
-                repository = &rhs[1..];
-                host = lhs;
+        let rep = self.repo.get(path, &[])?;
+        let next = rep
+            .header("Link")
+            .and_then(Self::link)
+            .and_then(|target| target.split_once('?'))
+            .map(|(.., query)| format!("tags/list?{}", query))
+            .filter(|next| next != path);
+
+        let page: Page = rep.into_json()?;
+        Ok((page.tags.unwrap_or_default(), next))
+    }
+}
+
+impl Iterator for Pages<'_> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(tag) = self.page.next() {
+                return Some(Ok(tag));
             }
-        }
 
-        // Add the default prefix if necessary.
-        let path = match repository.find('/') {
-            None => format!("{}/{}", Self::DEFAULT_PREFIX, repository),
-            _ => repository.into(),
-        };
+            let path = self.next.take()?;
+            match self.fetch(&path) {
+                Ok((tags, next)) => {
+                    self.page = tags.into_iter();
+                    self.next = next;
+                }
 
-        // Substitute the aliases
-        for (from, into) in Self::ALIASES {
-            if host == *from {
-                host = *into;
-                break;
+                Err(e) => return Some(Err(e)),
             }
         }
+    }
+}
 
-        let out = Self {
-            host: host.into(),
-            path,
+impl Source for Repository {
+    fn tags(&self, n: Option<usize>, last: Option<&str>) -> Result<Tags<'_>> {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        if let Some(n) = n {
+            query.append_pair("n", &n.to_string());
+        }
+
+        if let Some(last) = last {
+            query.append_pair("last", last);
+        }
+
+        let path = match query.finish() {
+            query if query.is_empty() => "tags/list".into(),
+            query => format!("tags/list?{}", query),
         };
 
-        Ok((out, tag))
+        Ok(Box::new(Pages {
+            repo: self,
+            next: Some(path),
+            page: Vec::new().into_iter(),
+        }))
     }
 
-    pub fn tags(&self) -> Result<Vec<String>> {
-        #[derive(Debug, Deserialize)]
-        struct Tags {
-            #[allow(dead_code)]
-            name: String,
-            tags: Vec<String>,
-        }
+    fn manifest(&self, reference: &str) -> Result<Fetched> {
+        let path = format!("manifests/{}", reference);
+        let accept = Manifest::MEDIA_TYPES.join(", ");
+
+        let rep = self.get(&path, &[("Accept", &accept)])?;
+        let media_type = rep.header("Content-Type").map(String::from);
+        let digest = match rep.header("Docker-Content-Digest") {
+            Some(digest) => Some(digest.parse().context("invalid Docker-Content-Digest")?),
+            None => None,
+        };
+
+        Ok(Fetched {
+            media_type,
+            digest,
+            reader: Box::new(rep.into_reader()),
+        })
+    }
+
+    fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+        let path = format!("blobs/{}", digest);
+
+        let rep = self.get(&path, &[])?;
+        let len = rep.header("Content-Length").and_then(|s| s.parse().ok());
+        Ok((len, Box::new(rep.into_reader())))
+    }
+
+    /// Resumes a blob with a `Range` request
+    ///
+    /// If the registry ignores the range, the blob is fetched again from the
+    /// start and the bytes we already have are skipped.
+    fn blob_at(&self, digest: &Digest, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let path = format!("blobs/{}", digest);
+        let range = format!("bytes={}-", offset);
+
+        let rep = self.get(&path, &[("Range", &range)])?;
+        ranged(&format!("{}/{}", self, path), rep, offset)
+    }
+}
 
-        let rep = self.get("tags/list", &[])?;
-        let tags: Tags = rep.into_json()?;
-        Ok(tags.tags)
+/// Reads the response to a `Range: bytes=<offset>-` request
+///
+/// If the server ignored the range, the bytes before `offset` are skipped.
+pub(super) fn ranged(
+    what: &dyn Display,
+    rep: Response,
+    offset: u64,
+) -> Result<Box<dyn Read + Send>> {
+    if rep.status() != 206 {
+        warn!("{}: range requests unsupported; restarting", what);
+        return skip(Box::new(rep.into_reader()), offset);
     }
 
-    pub fn image(&self, tag: &str) -> Result<Image> {
-        Image::new(self.clone(), tag)
+    // Content-Range: bytes <first>-<last>/<length>
+    let first = rep
+        .header("Content-Range")
+        .and_then(|cr| cr.strip_prefix("bytes "))
+        .and_then(|cr| cr.split_once('-'))
+        .and_then(|(first, ..)| first.parse::<u64>().ok());
+
+    match first {
+        Some(first) if first == offset => Ok(Box::new(rep.into_reader())),
+        _ => Err(anyhow!("{}: invalid range in response", what)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pages;
+
+    #[test]
+    fn link() {
+        let header = r#"</v2/foo/tags/list?n=2&last=b>; rel="next""#;
+        assert_eq!(Pages::link(header), Some("/v2/foo/tags/list?n=2&last=b"));
+
+        let header = r#"<https://ghcr.io/v2/foo/tags/list?last=b&n=2>; rel=next"#;
+        assert_eq!(
+            Pages::link(header),
+            Some("https://ghcr.io/v2/foo/tags/list?last=b&n=2")
+        );
+
+        let header = r#"</v2/foo/tags/list?n=2>; rel="prev", </v2/foo/tags/list?n=2&last=d>; type="x"; rel="last next""#;
+        assert_eq!(Pages::link(header), Some("/v2/foo/tags/list?n=2&last=d"));
+
+        assert_eq!(Pages::link(r#"</v2/foo>; rel="prev""#), None);
+        assert_eq!(Pages::link("garbage"), None);
     }
 }
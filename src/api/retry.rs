@@ -0,0 +1,226 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::fmt::Display;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use ring::rand::{SecureRandom, SystemRandom};
+use ureq::{Error, ErrorKind, Response};
+
+/// The number of attempts made before giving up
+const ATTEMPTS: u32 = 6;
+
+/// The delay before the first retry, which doubles with each attempt
+const BASE: Duration = Duration::from_millis(500);
+
+/// The longest delay between attempts that we choose ourselves
+const CAP: Duration = Duration::from_secs(30);
+
+/// The longest that we wait, even if `Retry-After` asks for more
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(600);
+
+/// Whether a request that failed with `err` may succeed if we try again
+fn transient(err: &Error) -> bool {
+    match err {
+        Error::Status(code, ..) => matches!(code, 408 | 429 | 500 | 502 | 503 | 504),
+        Error::Transport(..) => matches!(err.kind(), ErrorKind::ConnectionFailed | ErrorKind::Io),
+    }
+}
+
+/// Parses a `Retry-After` header: either seconds or an HTTP date
+fn retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    // IMF-fixdate, i.e. `Sun, 06 Nov 1994 08:49:37 GMT`
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let (.., date) = value.split_once(", ")?;
+    let (day, month, year, time) = match date.split(' ').collect::<Vec<_>>()[..] {
+        [day, month, year, time, "GMT"] => (day, month, year, time),
+        _ => return None,
+    };
+
+    let day: i64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as i64 + 1;
+    let year: i64 = year.parse().ok()?;
+    let mut hms = time.split(':').map(|n| n.parse::<i64>().ok());
+    let (h, m, s) = (hms.next()??, hms.next()??, hms.next()??);
+
+    // Days since the epoch in the proleptic Gregorian calendar
+    let (y, mp) = match month > 2 {
+        true => (year, month - 3),
+        false => (year - 1, month + 9),
+    };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400 + h * 3600 + m * 60 + s;
+    let then = UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?);
+    Some(then.duration_since(now).unwrap_or_default())
+}
+
+/// Parses a `RateLimit-*` header value, i.e. `100;w=21600`
+///
+/// Returns the count and the length of the window, if given.
+fn rate_limit(value: &str) -> Option<(u64, Option<Duration>)> {
+    let mut parts = value.split(';').map(str::trim);
+    let count = parts.next()?.parse().ok()?;
+    let window = parts
+        .filter_map(|p| p.strip_prefix("w="))
+        .find_map(|w| w.parse().ok())
+        .map(Duration::from_secs);
+
+    Some((count, window))
+}
+
+/// Explains a response from a registry that has throttled us
+///
+/// Docker Hub reports its pull limits in the `RateLimit-*` headers.
+fn throttled(what: &dyn Display, rep: &Response) {
+    let remaining = rep.header("RateLimit-Remaining").and_then(rate_limit);
+    if !matches!(remaining, Some((0, ..))) && rep.status() != 429 {
+        return;
+    }
+
+    match rep.header("RateLimit-Limit").and_then(rate_limit) {
+        Some((limit, Some(window))) => warn!(
+            "{}: rate limit exceeded ({} pulls per {} hours); log in or wait for the limit to reset",
+            what,
+            limit,
+            window.as_secs() / 3600
+        ),
+
+        _ => warn!("{}: rate limit exceeded; log in or try again later", what),
+    }
+}
+
+/// An exponential backoff with full jitter
+struct Backoff {
+    attempt: u32,
+    random: SystemRandom,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            attempt: 0,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// The delay before retrying after `err`, or `None` to give up
+    fn next(&mut self, err: &Error) -> Option<Duration> {
+        self.attempt += 1;
+        if self.attempt >= ATTEMPTS || !transient(err) {
+            return None;
+        }
+
+        // An unparsable `Retry-After` falls back to our own backoff.
+        if let Error::Status(.., rep) = err {
+            let now = SystemTime::now();
+            if let Some(delay) = rep.header("Retry-After").and_then(|v| retry_after(v, now)) {
+                return Some(delay.min(MAX_RETRY_AFTER));
+            }
+        }
+
+        let ceiling = BASE.saturating_mul(1 << (self.attempt - 1)).min(CAP);
+        let mut bytes = [0u8; 4];
+        self.random.fill(&mut bytes).ok()?;
+        let fraction = f64::from(u32::from_le_bytes(bytes)) / f64::from(u32::MAX);
+        Some(ceiling.mul_f64(fraction.max(0.1)))
+    }
+}
+
+/// Sends a request, retrying transient failures with backoff
+///
+/// Server errors, throttling and dropped connections are retried, honoring
+/// any `Retry-After` from the server. Other failures are returned at once.
+pub fn retry(
+    what: &dyn Display,
+    mut send: impl FnMut() -> Result<Response, Box<Error>>,
+) -> Result<Response, Box<Error>> {
+    let mut backoff = Backoff::new();
+
+    loop {
+        let err = match send() {
+            Ok(rep) => return Ok(rep),
+            Err(err) => err,
+        };
+
+        if let Error::Status(.., rep) = &*err {
+            throttled(what, rep);
+        }
+
+        match backoff.next(&err) {
+            None => return Err(err),
+            Some(delay) => {
+                warn!("{}: {}; retrying in {:.1}s", what, err, delay.as_secs_f64());
+                std::thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rate_limit, retry_after, Backoff, BASE, MAX_RETRY_AFTER};
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use ureq::{Error, Response};
+
+    fn unavailable(after: &str) -> Error {
+        let head = format!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\n\r\n",
+            after
+        );
+        let rep: Response = head.parse().unwrap();
+        Error::Status(503, rep)
+    }
+
+    #[test]
+    fn headers() {
+        let now = UNIX_EPOCH + Duration::from_secs(1445412470);
+        assert_eq!(retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon", now), None);
+        assert_eq!(retry_after("Wed, 21 Oct 2015 07:28 GMT", now), None);
+
+        assert_eq!(
+            rate_limit("100;w=21600"),
+            Some((100, Some(Duration::from_secs(21600))))
+        );
+        assert_eq!(rate_limit("0"), Some((0, None)));
+        assert_eq!(rate_limit(""), None);
+    }
+
+    #[test]
+    fn backoff() {
+        // Fall back to our own backoff if we can't parse `Retry-After`...
+        let delay = Backoff::new().next(&unavailable("soon")).unwrap();
+        assert!(delay <= BASE);
+
+        // ...and don't wait for longer than we are willing to.
+        let delay = Backoff::new().next(&unavailable("3600")).unwrap();
+        assert_eq!(delay, MAX_RETRY_AFTER);
+
+        let delay = Backoff::new().next(&unavailable("5")).unwrap();
+        assert_eq!(delay, Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,233 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::tree::{Tarball, Tree};
+use super::{listed, Fetched, Source, Tags};
+use crate::formats::Digest;
+
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+
+#[derive(Clone, Debug, Deserialize)]
+struct Entry {
+    #[serde(rename = "Config")]
+    config: String,
+
+    #[serde(default, rename = "RepoTags")]
+    repo_tags: Vec<String>,
+
+    #[serde(rename = "Layers")]
+    layers: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RootFs {
+    diff_ids: Vec<Digest>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Config {
+    rootfs: RootFs,
+}
+
+/// A tarball created by `docker save`
+///
+/// The archive contains no registry manifests. Instead, we synthesize a
+/// Docker v2 manifest for each image from its config and its uncompressed
+/// layers, whose digests are the `diff_ids` listed in the config.
+#[derive(Debug)]
+pub struct DockerArchive {
+    tree: Tarball,
+    entries: Vec<Entry>,
+    manifests: Vec<String>,
+    blobs: HashMap<String, String>,
+}
+
+impl Display for DockerArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "docker-archive:{}", self.tree)
+    }
+}
+
+impl DockerArchive {
+    const CONFIG: &'static str = "application/vnd.docker.container.image.v1+json";
+    const LAYER: &'static str = "application/vnd.docker.image.rootfs.diff.tar";
+    const MANIFEST: &'static str = "application/vnd.docker.distribution.manifest.v2+json";
+
+    pub fn new(tree: Tarball) -> Result<Self> {
+        let (.., reader) = tree.open("manifest.json")?;
+        let entries: Vec<Entry> = serde_json::from_reader(reader)?;
+
+        let mut manifests = Vec::new();
+        let mut blobs = HashMap::new();
+        for entry in entries.iter() {
+            let (size, mut reader) = tree.open(&entry.config)?;
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+
+            let digest = Digest::sha256(&bytes);
+            let config: Config = serde_json::from_slice(&bytes)?;
+            if config.rootfs.diff_ids.len() != entry.layers.len() {
+                return Err(anyhow!("{}: layer count mismatch", entry.config));
+            }
+
+            let mut layers = Vec::new();
+            for (path, digest) in entry.layers.iter().zip(config.rootfs.diff_ids.iter()) {
+                let (size, ..) = tree.open(path)?;
+                layers.push(format!(
+                    r#"{{"mediaType":"{}","size":{},"digest":"{}"}}"#,
+                    Self::LAYER,
+                    size,
+                    digest
+                ));
+                blobs.insert(digest.to_string(), path.clone());
+            }
+
+            manifests.push(format!(
+                r#"{{"schemaVersion":2,"mediaType":"{}","config":{{"mediaType":"{}","size":{},"digest":"{}"}},"layers":[{}]}}"#,
+                Self::MANIFEST,
+                Self::CONFIG,
+                size,
+                digest,
+                layers.join(",")
+            ));
+            blobs.insert(digest.to_string(), entry.config.clone());
+        }
+
+        Ok(Self {
+            tree,
+            entries,
+            manifests,
+            blobs,
+        })
+    }
+
+    /// The reference to use when none was given
+    ///
+    /// This is only possible when the archive contains a single image.
+    pub fn default_reference(&self) -> Result<String> {
+        match self.entries.len() {
+            1 => Ok("@0".into()),
+            0 => Err(anyhow!("{}: archive contains no images", self)),
+            _ => Err(anyhow!("{}: archive contains multiple images", self)),
+        }
+    }
+}
+
+impl Source for DockerArchive {
+    fn tags(&self, _: Option<usize>, last: Option<&str>) -> Result<Tags<'_>> {
+        let tags = self
+            .entries
+            .iter()
+            .flat_map(|e| e.repo_tags.clone())
+            .collect();
+
+        Ok(listed(tags, last))
+    }
+
+    /// Finds an image by `@INDEX` or by one of its `name:tag` pairs
+    fn manifest(&self, reference: &str) -> Result<Fetched> {
+        let index = match reference.strip_prefix('@') {
+            Some(index) => index.parse().ok(),
+            None => self
+                .entries
+                .iter()
+                .position(|e| e.repo_tags.iter().any(|t| t == reference)),
+        };
+
+        match index.and_then(|i| self.manifests.get(i)) {
+            Some(manifest) => Ok(Fetched {
+                media_type: Some(Self::MANIFEST.into()),
+                digest: None,
+                reader: Box::new(Cursor::new(manifest.clone().into_bytes())),
+            }),
+            None => Err(anyhow!("{}: reference not found: {}", self, reference)),
+        }
+    }
+
+    fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+        match self.blobs.get(&digest.to_string()) {
+            Some(path) => self.tree.open(path).map(|(len, r)| (Some(len), r)),
+            None => Err(anyhow!("{}: blob not found: {}", self, digest)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::tree::test::tarball;
+    use super::super::tree::Tarball;
+    use super::super::Source;
+    use super::DockerArchive;
+    use crate::formats::{Digest, Manifest};
+
+    use std::io::Read;
+
+    #[test]
+    fn synthesize() {
+        let layer = b"not really a tarball";
+        let diff_id = Digest::sha256(layer);
+        let config = format!(
+            r#"{{"rootfs": {{"type": "layers", "diff_ids": ["{}"]}}}}"#,
+            diff_id
+        );
+        let manifest = r#"[{"Config": "c0ffee.json", "RepoTags": ["boot:latest", "boot:1"], "Layers": ["0123/layer.tar"]}]"#;
+
+        let path = tarball(
+            "archive",
+            &[
+                ("manifest.json", manifest.as_bytes()),
+                ("c0ffee.json", config.as_bytes()),
+                ("0123/layer.tar", layer),
+            ],
+            &[],
+        );
+
+        let archive = DockerArchive::new(Tarball::new(&path).unwrap()).unwrap();
+        assert_eq!(archive.default_reference().unwrap(), "@0");
+
+        let tags: Vec<_> = archive
+            .tags(None, None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tags, ["boot:1", "boot:latest"]);
+
+        for reference in ["@0", "boot:latest"] {
+            let mut fetched = archive.manifest(reference).unwrap();
+            let mut bytes = Vec::new();
+            fetched.reader.read_to_end(&mut bytes).unwrap();
+
+            let m = match Manifest::parse(fetched.media_type.as_deref(), &bytes).unwrap() {
+                Manifest::DockerV2(m) => m,
+                m => panic!("unexpected manifest: {:?}", m),
+            };
+
+            assert!(m.config.digest.verify(config.as_bytes()));
+            assert_eq!(m.config.size, config.len() as u64);
+            assert_eq!(m.layers.len(), 1);
+            assert_eq!(m.layers[0].digest.to_string(), diff_id.to_string());
+            assert_eq!(m.layers[0].size, layer.len() as u64);
+
+            let (.., mut reader) = archive.blob(&m.config.digest).unwrap();
+            let mut blob = Vec::new();
+            reader.read_to_end(&mut blob).unwrap();
+            assert_eq!(blob, config.as_bytes());
+        }
+
+        let (len, mut reader) = archive.blob(&diff_id).unwrap();
+        let mut blob = Vec::new();
+        reader.read_to_end(&mut blob).unwrap();
+        assert_eq!((len, &blob[..]), (Some(layer.len() as u64), &layer[..]));
+
+        assert!(archive.manifest("@1").is_err());
+        assert!(archive.manifest("boot:2").is_err());
+        assert!(archive.blob(&Digest::sha256(b"missing")).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::tree::{Directory, Tree};
+use super::{Fetched, Source, Tags};
+use crate::formats::Digest;
+
+use std::fmt::Display;
+use std::io::{Cursor, Read};
+
+use anyhow::{anyhow, Result};
+
+/// An image stored in the containers `dir:` format
+///
+/// The directory contains a single `manifest.json` and one file per blob,
+/// named by the hex encoding of the blob's digest.
+#[derive(Debug)]
+pub struct Dir(Directory);
+
+impl Display for Dir {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "dir:{}", self.0)
+    }
+}
+
+impl Dir {
+    const MANIFEST: &'static str = "manifest.json";
+
+    fn path(digest: &Digest) -> String {
+        let digest = digest.to_string();
+        match digest.split_once(':') {
+            Some(("sha256", hex)) => hex.into(),
+            _ => digest.replacen(':', "-", 1),
+        }
+    }
+
+    pub fn new(tree: Directory) -> Self {
+        Self(tree)
+    }
+
+    /// The reference to use when none was given
+    ///
+    /// This is the digest of the only manifest in the directory.
+    pub fn default_reference(&self) -> Result<String> {
+        let mut manifest = Vec::new();
+        self.0.open(Self::MANIFEST)?.1.read_to_end(&mut manifest)?;
+        Ok(Digest::sha256(&manifest).to_string())
+    }
+}
+
+impl Source for Dir {
+    fn tags(&self, _: Option<usize>, _: Option<&str>) -> Result<Tags<'_>> {
+        Err(anyhow!("{}: listing tags is not supported", self))
+    }
+
+    /// The format doesn't record media types, so the manifests carry their own
+    fn manifest(&self, reference: &str) -> Result<Fetched> {
+        let fetched = |reader| Fetched {
+            media_type: None,
+            digest: None,
+            reader,
+        };
+
+        let digest = match reference.parse::<Digest>() {
+            Ok(digest) => digest,
+            Err(..) => return Ok(fetched(self.0.open(Self::MANIFEST)?.1)),
+        };
+
+        // Manifests other than the top-level one are stored as blobs.
+        if let Ok((.., reader)) = self.0.open(&Self::path(&digest)) {
+            return Ok(fetched(reader));
+        }
+
+        let mut manifest = Vec::new();
+        self.0.open(Self::MANIFEST)?.1.read_to_end(&mut manifest)?;
+        match digest.verify(&manifest) {
+            true => Ok(fetched(Box::new(Cursor::new(manifest)))),
+            false => Err(anyhow!("{}: manifest not found: {}", self, digest)),
+        }
+    }
+
+    fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+        let (len, reader) = self.0.open(&Self::path(digest))?;
+        Ok((Some(len), reader))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::tree::Directory;
+    use super::super::Source;
+    use super::Dir;
+    use crate::formats::Digest;
+
+    use std::io::Read;
+
+    #[test]
+    fn manifest() {
+        let list = br#"{"schemaVersion": 2, "manifests": []}"#;
+        let image = br#"{"schemaVersion": 2, "config": {}}"#;
+
+        let path = std::env::temp_dir().join(format!("wyrcan-dir-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("manifest.json"), list).unwrap();
+        std::fs::write(path.join(Dir::path(&Digest::sha256(image))), image).unwrap();
+
+        let dir = Dir::new(Directory::new(&path).unwrap());
+        let fetch = |reference: &str| -> anyhow::Result<Vec<u8>> {
+            let mut bytes = Vec::new();
+            dir.manifest(reference)?.reader.read_to_end(&mut bytes)?;
+            Ok(bytes)
+        };
+
+        let reference = dir.default_reference().unwrap();
+        assert_eq!(fetch(&reference).unwrap(), list);
+        assert_eq!(fetch(&Digest::sha256(image).to_string()).unwrap(), image);
+        assert!(fetch(&Digest::sha256(b"missing").to_string()).is_err());
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
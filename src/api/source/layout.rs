@@ -0,0 +1,190 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::tree::Tree;
+use super::{listed, Fetched, Source, Tags};
+use crate::formats::{oci::Index, Digest};
+
+use std::fmt::Display;
+use std::io::Read;
+
+use anyhow::{anyhow, Result};
+
+/// An OCI image layout stored in a directory or a tarball
+#[derive(Debug)]
+pub struct Layout<T: Tree> {
+    transport: &'static str,
+    tree: T,
+    index: Index,
+}
+
+impl<T: Tree> Display for Layout<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.transport, self.tree)
+    }
+}
+
+impl<T: Tree> Layout<T> {
+    const REF_NAME: &'static str = "org.opencontainers.image.ref.name";
+
+    fn path(digest: &Digest) -> String {
+        format!("blobs/{}", digest.to_string().replacen(':', "/", 1))
+    }
+
+    pub fn new(transport: &'static str, tree: T) -> Result<Self> {
+        let (.., index) = tree.open("index.json")?;
+        let index = serde_json::from_reader(index)?;
+        Ok(Self {
+            transport,
+            tree,
+            index,
+        })
+    }
+
+    /// The reference to use when none was given
+    ///
+    /// This is only possible when the layout contains a single manifest.
+    pub fn default_reference(&self) -> Result<String> {
+        match &self.index.manifests[..] {
+            [one] => Ok(one.digest.to_string()),
+            [] => Err(anyhow!("{}: layout contains no manifests", self)),
+            _ => Err(anyhow!("{}: layout contains multiple manifests", self)),
+        }
+    }
+}
+
+impl<T: Tree> Source for Layout<T> {
+    fn tags(&self, _: Option<usize>, last: Option<&str>) -> Result<Tags<'_>> {
+        let tags = self
+            .index
+            .manifests
+            .iter()
+            .filter_map(|m| m.annotations.get(Self::REF_NAME).cloned())
+            .collect();
+
+        Ok(listed(tags, last))
+    }
+
+    fn manifest(&self, reference: &str) -> Result<Fetched> {
+        if let Ok(digest) = reference.parse::<Digest>() {
+            let media_type = self
+                .index
+                .manifests
+                .iter()
+                .find(|m| m.digest.to_string() == digest.to_string())
+                .map(|m| m.media_type.clone());
+
+            let (.., reader) = self.tree.open(&Self::path(&digest))?;
+            return Ok(Fetched {
+                media_type,
+                digest: Some(digest),
+                reader,
+            });
+        }
+
+        let found = self
+            .index
+            .manifests
+            .iter()
+            .find(|m| m.annotations.get(Self::REF_NAME).map(|s| &**s) == Some(reference));
+
+        match found {
+            Some(m) => Ok(Fetched {
+                media_type: Some(m.media_type.clone()),
+                digest: Some(m.digest.clone()),
+                reader: self.tree.open(&Self::path(&m.digest))?.1,
+            }),
+            None => Err(anyhow!("{}: reference not found: {}", self, reference)),
+        }
+    }
+
+    fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+        let (len, reader) = self.tree.open(&Self::path(digest))?;
+        Ok((Some(len), reader))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::tree::test::tarball;
+    use super::super::tree::Tarball;
+    use super::super::Source;
+    use super::Layout;
+    use crate::formats::{Digest, Manifest};
+
+    use std::io::Read;
+
+    #[test]
+    fn references() {
+        let first = br#"{"schemaVersion": 2, "manifests": []}"#;
+        let second = br#"{"schemaVersion": 2, "manifests": [], "annotations": {}}"#;
+        let (a, b) = (Digest::sha256(first), Digest::sha256(second));
+
+        let index = format!(
+            r#"{{"schemaVersion": 2, "manifests": [
+                {{"mediaType": "{}", "digest": "{}", "size": {},
+                  "annotations": {{"org.opencontainers.image.ref.name": "first"}}}},
+                {{"mediaType": "{}", "digest": "{}", "size": {},
+                  "annotations": {{"org.opencontainers.image.ref.name": "second"}}}}
+            ]}}"#,
+            Manifest::OCI_INDEX,
+            a,
+            first.len(),
+            Manifest::OCI,
+            b,
+            second.len()
+        );
+
+        let (pa, pb) = (Layout::<Tarball>::path(&a), Layout::<Tarball>::path(&b));
+        let path = tarball(
+            "layout",
+            &[
+                ("oci-layout", br#"{"imageLayoutVersion": "1.0.0"}"#),
+                ("index.json", index.as_bytes()),
+                (&pa, first),
+                (&pb, second),
+            ],
+            &[],
+        );
+
+        let layout = Layout::new("oci-archive", Tarball::new(&path).unwrap()).unwrap();
+        assert!(layout.default_reference().is_err());
+
+        let tags: Vec<_> = layout
+            .tags(None, Some("first"))
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tags, ["second"]);
+
+        let fetch = |reference: &str| {
+            let mut fetched = layout.manifest(reference).unwrap();
+            let mut bytes = Vec::new();
+            fetched.reader.read_to_end(&mut bytes).unwrap();
+            (
+                fetched.media_type,
+                fetched.digest.map(|d| d.to_string()),
+                bytes,
+            )
+        };
+
+        let by_name = fetch("second");
+        assert_eq!(
+            by_name,
+            (
+                Some(Manifest::OCI.into()),
+                Some(b.to_string()),
+                second.to_vec()
+            )
+        );
+        assert_eq!(fetch(&b.to_string()), by_name);
+        assert_eq!(fetch("first").0.as_deref(), Some(Manifest::OCI_INDEX));
+
+        assert!(layout.manifest("third").is_err());
+        assert!(layout
+            .manifest(&Digest::sha256(b"third").to_string())
+            .is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! Transports that images can be fetched from
+//!
+//! A source name has the form `[transport:]name[:tag|@digest]`. Supported
+//! transports are:
+//!
+//!   * `docker://` - a container registry (the default)
+//!   * `oci:<dir>[:ref]` - an OCI image layout in a directory
+//!   * `oci-archive:<file>[:ref]` - an OCI image layout in a tarball
+//!   * `docker-archive:<file>[:name:tag|:@index]` - a `docker save` tarball
+//!   * `dir:<dir>` - an image in the containers `dir:` format
+
+mod archive;
+mod dir;
+mod layout;
+mod tree;
+
+use self::archive::DockerArchive;
+use self::dir::Dir;
+use self::layout::Layout;
+use self::tree::{Directory, Tarball};
+use super::Repository;
+use crate::formats::Digest;
+
+use std::fmt::{Debug, Display};
+use std::io::Read;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+/// A manifest as fetched from a source
+pub struct Fetched {
+    /// The media type of the manifest, if the source knows it
+    pub media_type: Option<String>,
+
+    /// The digest that the source claims for the manifest, if any
+    pub digest: Option<Digest>,
+
+    pub reader: Box<dyn Read + Send>,
+}
+
+/// A stream of tags, which may be fetched lazily
+pub type Tags<'a> = Box<dyn Iterator<Item = Result<String>> + 'a>;
+
+/// Lists the tags that sort after `last`, in lexical order
+fn listed(mut tags: Vec<String>, last: Option<&str>) -> Tags<'static> {
+    tags.sort();
+    tags.dedup();
+
+    let last = last.map(String::from);
+    Box::new(
+        tags.into_iter()
+            .filter(move |t| last.as_deref().is_none_or(|l| &**t > l))
+            .map(Ok),
+    )
+}
+
+/// A place that manifests and blobs can be fetched from
+pub trait Source: Debug + Display + Send + Sync {
+    /// Lists the tags available in this source
+    ///
+    /// Only tags sorting after `last` are listed. Sources that fetch the
+    /// tags in pages fetch up to `n` tags at a time.
+    fn tags(&self, n: Option<usize>, last: Option<&str>) -> Result<Tags<'_>>;
+
+    /// Fetches the manifest for the given tag or digest along with its
+    /// media type and digest, if known
+    fn manifest(&self, reference: &str) -> Result<Fetched>;
+
+    /// Fetches the blob with the given digest along with its length, if known
+    fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)>;
+
+    /// Fetches the blob with the given digest, starting at `offset`
+    ///
+    /// By default, the whole blob is fetched and the start is skipped.
+    fn blob_at(&self, digest: &Digest, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let (.., reader) = self.blob(digest)?;
+        skip(reader, offset)
+    }
+}
+
+/// Skips the first `offset` bytes of `reader`
+pub(super) fn skip(mut reader: Box<dyn Read + Send>, offset: u64) -> Result<Box<dyn Read + Send>> {
+    let skipped = std::io::copy(&mut (&mut reader).take(offset), &mut std::io::sink())?;
+    match skipped == offset {
+        true => Ok(reader),
+        false => Err(anyhow!("blob ended at byte {} of {}", skipped, offset)),
+    }
+}
+
+/// Opens the source for `name` and returns it with the reference to use
+///
+/// When only `listing` tags, no reference is needed, so none is chosen.
+pub fn open(name: &str, listing: bool) -> Result<(Arc<dyn Source>, String)> {
+    // Splits `path[:reference]`, falling back to the source's default.
+    fn local<S: Source + 'static>(
+        rest: &str,
+        listing: bool,
+        new: impl FnOnce(&str) -> Result<S>,
+        default: impl FnOnce(&S) -> Result<String>,
+    ) -> Result<(Arc<dyn Source>, String)> {
+        let (path, reference) = match rest.split_once(':') {
+            Some((path, reference)) => (path, Some(reference.to_string())),
+            None => (rest, None),
+        };
+
+        let source = new(path)?;
+        let reference = match reference {
+            Some(reference) => reference,
+            None if listing => String::new(),
+            None => default(&source)?,
+        };
+
+        Ok((Arc::new(source), reference))
+    }
+
+    let (transport, rest) = name.split_once(':').unwrap_or((name, ""));
+    match transport {
+        "oci" => local(
+            rest,
+            listing,
+            |p| Layout::new("oci", Directory::new(p)?),
+            Layout::default_reference,
+        ),
+
+        "oci-archive" => local(
+            rest,
+            listing,
+            |p| Layout::new("oci-archive", Tarball::new(p)?),
+            Layout::default_reference,
+        ),
+
+        "docker-archive" => local(
+            rest,
+            listing,
+            |p| DockerArchive::new(Tarball::new(p)?),
+            DockerArchive::default_reference,
+        ),
+
+        "dir" => {
+            let dir = Dir::new(Directory::new(rest)?);
+            let reference = dir.default_reference()?;
+            Ok((Arc::new(dir), reference))
+        }
+
+        _ => {
+            let name = name.strip_prefix("docker://").unwrap_or(name);
+            let (repo, tag) = Repository::new(name, listing)?;
+            Ok((Arc::new(repo), tag))
+        }
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{Fetched, Source, Tags};
+    use crate::formats::Digest;
+
+    use std::collections::HashMap;
+    use std::io::{Cursor, Read};
+
+    use anyhow::{anyhow, Result};
+
+    /// A manifest's media type, the digest claimed for it and its body
+    type Stored = (String, Option<Digest>, Vec<u8>);
+
+    /// A source that serves manifests and blobs from memory
+    #[derive(Debug, Default)]
+    pub struct Memory {
+        manifests: HashMap<String, Stored>,
+        blobs: HashMap<String, Vec<u8>>,
+        truncate: bool,
+    }
+
+    impl std::fmt::Display for Memory {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("memory")
+        }
+    }
+
+    impl Memory {
+        /// Adds a manifest for `reference`, claiming `digest` if given
+        pub fn with_manifest(
+            mut self,
+            reference: &str,
+            media_type: &str,
+            digest: Option<Digest>,
+            body: &[u8],
+        ) -> Self {
+            let stored = (media_type.into(), digest, body.to_vec());
+            self.manifests.insert(reference.into(), stored);
+            self
+        }
+
+        /// Adds a blob, stored under its sha256 digest
+        pub fn with_blob(mut self, data: &[u8]) -> Self {
+            self.blobs
+                .insert(Digest::sha256(data).to_string(), data.to_vec());
+            self
+        }
+
+        /// Makes the first download of each blob stop halfway through
+        pub fn truncated(mut self) -> Self {
+            self.truncate = true;
+            self
+        }
+
+        fn find(&self, digest: &Digest) -> Result<&[u8]> {
+            match self.blobs.get(&digest.to_string()) {
+                Some(data) => Ok(data),
+                None => Err(anyhow!("{}: blob unknown", digest)),
+            }
+        }
+    }
+
+    impl Source for Memory {
+        fn tags(&self, _: Option<usize>, last: Option<&str>) -> Result<Tags<'_>> {
+            Ok(super::listed(
+                self.manifests.keys().cloned().collect(),
+                last,
+            ))
+        }
+
+        fn manifest(&self, reference: &str) -> Result<Fetched> {
+            let (media_type, digest, body) = self
+                .manifests
+                .get(reference)
+                .ok_or_else(|| anyhow!("{}: manifest unknown", reference))?;
+
+            Ok(Fetched {
+                media_type: Some(media_type.clone()),
+                digest: digest.clone(),
+                reader: Box::new(Cursor::new(body.clone())),
+            })
+        }
+
+        fn blob(&self, digest: &Digest) -> Result<(Option<u64>, Box<dyn Read + Send>)> {
+            let data = self.find(digest)?;
+            let len = data.len();
+            let end = if self.truncate { len / 2 } else { len };
+            Ok((
+                Some(len as u64),
+                Box::new(Cursor::new(data[..end].to_vec())),
+            ))
+        }
+
+        fn blob_at(&self, digest: &Digest, offset: u64) -> Result<Box<dyn Read + Send>> {
+            let data = self.find(digest)?;
+            Ok(Box::new(Cursor::new(data[offset as usize..].to_vec())))
+        }
+    }
+}
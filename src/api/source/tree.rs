@@ -0,0 +1,218 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use tar::{Archive, EntryType};
+
+/// A tree of files that a local source reads from
+pub trait Tree: Debug + Display + Send + Sync {
+    /// Opens the file at `path` and returns its length and contents
+    fn open(&self, path: &str) -> Result<(u64, Box<dyn Read + Send>)>;
+}
+
+/// A tree of files in a local directory
+#[derive(Clone, Debug)]
+pub struct Directory(PathBuf);
+
+impl Display for Directory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+impl Directory {
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        if !path.is_dir() {
+            return Err(anyhow!("not a directory: {:?}", path));
+        }
+
+        Ok(Self(path))
+    }
+}
+
+impl Tree for Directory {
+    fn open(&self, path: &str) -> Result<(u64, Box<dyn Read + Send>)> {
+        let file = File::open(self.0.join(path))?;
+        let len = file.metadata()?.len();
+        Ok((len, Box::new(file)))
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Entry {
+    File(u64, u64),
+    Link(String),
+}
+
+/// A tree of files in an uncompressed local tarball
+///
+/// The tarball is indexed once when opened. After that, each file is read by
+/// seeking directly to its contents.
+#[derive(Clone, Debug)]
+pub struct Tarball {
+    path: PathBuf,
+    entries: HashMap<String, Entry>,
+}
+
+impl Display for Tarball {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+impl Tarball {
+    const MAX_LINKS: usize = 16;
+
+    fn normalize(path: &Path) -> String {
+        let path = path.to_string_lossy();
+        path.trim_start_matches("./").trim_end_matches('/').into()
+    }
+
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let mut entries = HashMap::new();
+
+        let mut archive = Archive::new(File::open(&path)?);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = Self::normalize(&entry.path()?);
+
+            let entry = match entry.header().entry_type() {
+                EntryType::Regular | EntryType::Continuous => {
+                    Entry::File(entry.raw_file_position(), entry.size())
+                }
+
+                EntryType::Symlink | EntryType::Link => match entry.link_name()? {
+                    Some(link) if entry.header().entry_type() == EntryType::Symlink => {
+                        let parent = Path::new(&name).parent().unwrap_or_else(|| "".as_ref());
+                        Entry::Link(Self::normalize(&parent.join(link)))
+                    }
+
+                    Some(link) => Entry::Link(Self::normalize(&link)),
+                    None => continue,
+                },
+
+                _ => continue,
+            };
+
+            entries.insert(name, entry);
+        }
+
+        Ok(Self { path, entries })
+    }
+}
+
+impl Tree for Tarball {
+    fn open(&self, path: &str) -> Result<(u64, Box<dyn Read + Send>)> {
+        let mut name = Self::normalize(path.as_ref());
+
+        for _ in 0..Self::MAX_LINKS {
+            // Resolve any `..` components left over from relative symlinks.
+            let mut parts = Vec::new();
+            for part in name.split('/') {
+                match part {
+                    ".." => drop(parts.pop()),
+                    "." | "" => (),
+                    _ => parts.push(part),
+                }
+            }
+            name = parts.join("/");
+
+            match self.entries.get(&name) {
+                Some(Entry::File(offset, size)) => {
+                    let mut file = File::open(&self.path)?;
+                    file.seek(SeekFrom::Start(*offset))?;
+                    return Ok((*size, Box::new(file.take(*size))));
+                }
+
+                Some(Entry::Link(link)) => name = link.clone(),
+                None => return Err(anyhow!("{}: file not found: {}", self, path)),
+            }
+        }
+
+        Err(anyhow!("{}: too many levels of links: {}", self, path))
+    }
+}
+
+#[cfg(test)]
+pub(super) mod test {
+    use super::{Tarball, Tree};
+
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    use tar::{Builder, EntryType, Header};
+
+    /// Writes a tarball of `(path, contents)` files and `(path, kind, target)`
+    /// links to a temporary file
+    pub fn tarball(
+        name: &str,
+        files: &[(&str, &[u8])],
+        links: &[(&str, EntryType, &str)],
+    ) -> PathBuf {
+        let mut builder = Builder::new(Vec::new());
+
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+
+        for (path, kind, target) in links {
+            let mut header = Header::new_gnu();
+            header.set_entry_type(*kind);
+            header.set_link_name(target).unwrap();
+            header.set_size(0);
+            builder.append_data(&mut header, path, &[][..]).unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("wyrcan-{}-{}.tar", name, std::process::id()));
+        std::fs::write(&path, builder.into_inner().unwrap()).unwrap();
+        path
+    }
+
+    /// Reads the whole file at `path` in `tree`
+    pub fn read(tree: &dyn Tree, path: &str) -> Vec<u8> {
+        let (len, mut reader) = tree.open(path).unwrap();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(len, data.len() as u64);
+        data
+    }
+
+    #[test]
+    fn links() {
+        let path = tarball(
+            "links",
+            &[("data/first", b"first"), ("data/second", b"second")],
+            &[
+                ("blobs/sha256/first", EntryType::Symlink, "../../data/first"),
+                ("blobs/sha256/second", EntryType::Link, "data/second"),
+                ("blobs/latest", EntryType::Symlink, "sha256/first"),
+                ("loop/a", EntryType::Symlink, "b"),
+                ("loop/b", EntryType::Symlink, "a"),
+            ],
+        );
+
+        let tarball = Tarball::new(&path).unwrap();
+        assert_eq!(read(&tarball, "data/second"), b"second");
+        assert_eq!(read(&tarball, "./data/second"), b"second");
+        assert_eq!(read(&tarball, "blobs/sha256/first"), b"first");
+        assert_eq!(read(&tarball, "blobs/sha256/second"), b"second");
+        assert_eq!(read(&tarball, "blobs/latest"), b"first");
+        assert_eq!(read(&tarball, "blobs/../data/first"), b"first");
+
+        assert!(tarball.open("data/third").is_err());
+        assert!(tarball.open("loop/a").is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -3,6 +3,7 @@
 
 use super::extract::{Extract, LookAside};
 use super::Command;
+use crate::api::Platform;
 use crate::iotools::Either;
 
 use std::fs::File;
@@ -27,6 +28,10 @@ pub struct Convert {
     #[structopt(short, long)]
     cmdline: Option<PathBuf>,
 
+    /// The platform to select from multi-platform images (format: os/arch[/variant])
+    #[structopt(long, default_value)]
+    platform: Platform,
+
     /// Don't display the progress bar
     #[structopt(short, long)]
     quiet: bool,
@@ -51,6 +56,7 @@ impl Command for Convert {
             initrd: create(self.initrd.as_ref())?,
             cmdline: LookAside::cmdline(create(self.cmdline.as_ref())?),
             name: self.name,
+            platform: self.platform,
             progress: !self.quiet,
         };
 
@@ -3,7 +3,7 @@
 
 use super::unpacker::Unpacker;
 use super::Command;
-use crate::api::Repository;
+use crate::api::{open, Image, Platform};
 use crate::iotools::Muxer;
 
 use std::io::{Read, Write};
@@ -12,6 +12,7 @@ use std::path::PathBuf;
 
 use anyhow::{anyhow, Result};
 use libc::{S_IFLNK, S_IFMT, S_IFREG};
+use log::info;
 use tar::Header;
 
 #[derive(Debug)]
@@ -70,13 +71,15 @@ pub struct Extract<K: Write, I: Write, C: Write> {
     pub initrd: I,
     pub cmdline: LookAside<C>,
     pub name: String,
+    pub platform: Platform,
     pub progress: bool,
 }
 
 impl<K: Write, I: Write, C: Write> Command for Extract<K, I, C> {
     fn execute(self) -> anyhow::Result<()> {
-        let (repo, tag) = Repository::new(&self.name)?;
-        let image = repo.image(tag)?;
+        let (source, tag) = open(&self.name, false)?;
+        let image = Image::new(source, &tag, &self.platform)?;
+        info!("{}: resolved to {}", image, image.digest());
         let unpacker = Unpacker::new(&image, self.progress)?;
 
         let mut kernel = self.kernel;
@@ -129,6 +132,8 @@ impl<K: Write, I: Write, C: Write> Command for Extract<K, I, C> {
                 // Copy from the tarball to the cpio.
                 std::io::copy(&mut reader, &mut muxer)?;
             }
+
+            bundle.finish()?;
         }
 
         Ok(())
@@ -1,6 +1,8 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+#[cfg(any(target_arch = "aarch64", target_arch = "x86_64"))]
+use std::arch::asm;
 use std::ffi::CString;
 use std::fs::File;
 use std::os::unix::prelude::*;
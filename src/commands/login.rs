@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use crate::api::Credentials;
+
+use super::Command;
+
+use std::io::{BufRead, Read, Write};
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use structopt::StructOpt;
+
+/// Reads a line from stdin, after writing `prompt` to stderr
+fn prompt(prompt: &str) -> Result<String> {
+    eprint!("{}", prompt);
+    std::io::stderr().flush()?;
+
+    let mut line = String::new();
+    if std::io::stdin().lock().read_line(&mut line)? == 0 {
+        return Err(anyhow!("unexpected end of input"));
+    }
+
+    Ok(line.trim_end_matches(['\r', '\n']).into())
+}
+
+/// Like `prompt()`, but doesn't echo the input if stdin is a terminal
+fn secret(prompt: &str) -> Result<String> {
+    let fd = libc::STDIN_FILENO;
+    let mut termios = MaybeUninit::<libc::termios>::uninit();
+
+    // Not a terminal: just read the line.
+    if unsafe { libc::tcgetattr(fd, termios.as_mut_ptr()) } != 0 {
+        return self::prompt(prompt);
+    }
+
+    let saved = unsafe { termios.assume_init() };
+    let mut quiet = saved;
+    quiet.c_lflag &= !libc::ECHO;
+    quiet.c_lflag |= libc::ECHONL;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &quiet) };
+
+    let line = self::prompt(prompt);
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &saved) };
+    line
+}
+
+/// Log in to a container registry
+///
+/// The credentials are checked against the registry and then stored in the
+/// auth file, where they are used for later pulls.
+#[derive(StructOpt, Debug)]
+pub struct Login {
+    /// The registry to log in to (format: host[/namespace])
+    registry: String,
+
+    /// The username (prompted for if not given)
+    #[structopt(short, long)]
+    username: Option<String>,
+
+    /// The password (prompted for if not given)
+    #[structopt(short, long)]
+    password: Option<String>,
+
+    /// Read the password from stdin
+    #[structopt(long, conflicts_with = "password")]
+    password_stdin: bool,
+
+    /// The auth file to store the credentials in
+    #[structopt(long, parse(from_os_str))]
+    authfile: Option<PathBuf>,
+}
+
+impl Command for Login {
+    fn execute(self) -> anyhow::Result<()> {
+        let username = match self.username {
+            Some(username) => username,
+            None if self.password_stdin => {
+                return Err(anyhow!("--password-stdin needs --username"))
+            }
+            None => prompt("Username: ")?,
+        };
+
+        let password = match self.password {
+            Some(password) => password,
+            None if self.password_stdin => {
+                let mut password = String::new();
+                std::io::stdin().read_to_string(&mut password)?;
+                password.trim_end_matches(['\r', '\n']).into()
+            }
+            None => secret("Password: ")?,
+        };
+
+        if username.is_empty() || password.is_empty() {
+            return Err(anyhow!("username and password must not be empty"));
+        }
+
+        let credentials = Credentials::new(username, password);
+        credentials.check(&self.registry)?;
+
+        let authfile = match self.authfile {
+            Some(authfile) => authfile,
+            None => Credentials::default_file()?,
+        };
+
+        credentials.store(&self.registry, &authfile)?;
+        eprintln!("Login succeeded");
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use crate::api::Credentials;
+
+use super::Command;
+
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+use structopt::StructOpt;
+
+/// Log out of a container registry
+///
+/// This removes the stored credentials for the registry from the auth file.
+#[derive(StructOpt, Debug)]
+pub struct Logout {
+    /// The registry to log out of (format: host[/namespace])
+    registry: String,
+
+    /// The auth file to remove the credentials from
+    #[structopt(long, parse(from_os_str))]
+    authfile: Option<PathBuf>,
+}
+
+impl Command for Logout {
+    fn execute(self) -> anyhow::Result<()> {
+        let authfile = match self.authfile {
+            Some(authfile) => authfile,
+            None => Credentials::default_file()?,
+        };
+
+        match Credentials::erase(&self.registry, &authfile)? {
+            true => Ok(()),
+            false => Err(anyhow!(
+                "{:?}: not logged in to {}",
+                authfile,
+                self.registry
+            )),
+        }
+    }
+}
@@ -6,6 +6,8 @@ use structopt::StructOpt;
 mod convert;
 mod extract;
 mod kexec;
+mod login;
+mod logout;
 mod tags;
 mod unpack;
 mod unpacker;
@@ -21,6 +23,8 @@ pub enum Main {
     Kexec(kexec::Kexec),
     Unpack(unpack::Unpack),
     Convert(convert::Convert),
+    Login(login::Login),
+    Logout(logout::Logout),
 }
 
 impl Command for Main {
@@ -30,6 +34,8 @@ impl Command for Main {
             Self::Kexec(cmd) => cmd.execute(),
             Self::Unpack(cmd) => cmd.execute(),
             Self::Convert(cmd) => cmd.execute(),
+            Self::Login(cmd) => cmd.execute(),
+            Self::Logout(cmd) => cmd.execute(),
         }
     }
 }
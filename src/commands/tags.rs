@@ -1,7 +1,7 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-use crate::api::Repository;
+use crate::api::open;
 
 use super::Command;
 
@@ -12,14 +12,22 @@ use structopt::StructOpt;
 pub struct Tags {
     /// The repository name (format: [source]name)
     name: String,
+
+    /// Fetch tags from registries this many at a time
+    #[structopt(short = "n", long = "limit")]
+    limit: Option<usize>,
+
+    /// Only list the tags that sort after this one
+    #[structopt(long)]
+    last: Option<String>,
 }
 
 impl Command for Tags {
     fn execute(self) -> anyhow::Result<()> {
-        let (repo, ..) = Repository::new(&self.name)?;
+        let (source, ..) = open(&self.name, true)?;
 
-        for tag in repo.tags()? {
-            println!("{}", tag);
+        for tag in source.tags(self.limit, self.last.as_deref())? {
+            println!("{}", tag?);
         }
 
         Ok(())
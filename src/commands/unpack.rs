@@ -3,7 +3,7 @@
 
 use super::unpacker::Unpacker;
 use super::Command;
-use crate::api::Repository;
+use crate::api::{open, Image, Platform};
 
 use std::fs::{DirBuilder, OpenOptions};
 use std::io::Error;
@@ -13,7 +13,7 @@ use std::path::{Component, PathBuf};
 
 use anyhow::{anyhow, Result};
 use libc::{S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFMT, S_IFREG, S_IFSOCK};
-use log::warn;
+use log::{info, warn};
 use structopt::StructOpt;
 
 /// Unpacks a container into the given directory
@@ -25,6 +25,10 @@ pub struct Unpack {
     /// The output directory (will be created)
     output: PathBuf,
 
+    /// The platform to select from multi-platform images (format: os/arch[/variant])
+    #[structopt(long, default_value)]
+    platform: Platform,
+
     /// Don't display the progress bar
     #[structopt(short, long)]
     quiet: bool,
@@ -34,8 +38,9 @@ impl Command for Unpack {
     fn execute(self) -> Result<()> {
         std::fs::create_dir(&self.output)?;
 
-        let (repo, tag) = Repository::new(&self.name)?;
-        let image = repo.image(tag)?;
+        let (source, tag) = open(&self.name, false)?;
+        let image = Image::new(source, &tag, &self.platform)?;
+        info!("{}: resolved to {}", image, image.digest());
         let unpacker = Unpacker::new(&image, !self.quiet)?;
 
         for mut bundle in unpacker.bundles()? {
@@ -124,6 +129,8 @@ impl Command for Unpack {
                     _ => return Err(anyhow!("unknown mode ({:o}) on {:?}", mode, &path).into()),
                 }
             }
+
+            bundle.finish()?;
         }
 
         Ok(())
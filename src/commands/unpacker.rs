@@ -2,6 +2,7 @@
 // Copyright (C) 2021 Profian, Inc.
 
 use crate::api::{Image, Layer};
+use crate::formats::Digest;
 use crate::iotools::threaded;
 
 use std::collections::HashSet;
@@ -11,12 +12,13 @@ use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 use std::thread::spawn;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
 use tar::{Archive, Entry};
 
 pub struct Bundle<'a, T: Read> {
     unpacker: &'a Unpacker,
+    digest: Digest,
     archive: Archive<T>,
 }
 
@@ -41,6 +43,18 @@ impl<'a, T: Read> Bundle<'a, T> {
                 .transpose()
             }))
     }
+
+    /// Reads whatever follows the end of the archive
+    ///
+    /// The layer is validated when its stream ends, but the tar archive ends
+    /// before its stream does (i.e. there is padding after it). So we have to
+    /// read to the end, or corrupt layers would go unnoticed.
+    pub fn finish(self) -> Result<()> {
+        let digest = self.digest;
+        std::io::copy(&mut self.archive.into_inner(), &mut std::io::sink())
+            .with_context(|| format!("{}: invalid layer", digest))?;
+        Ok(())
+    }
 }
 
 pub struct Unpacker {
@@ -94,11 +108,12 @@ impl Unpacker {
 
             let src = progress.wrap_read(src);
             let src = threaded::Reader::new(src);
-            let src = layer.decompressor(BufReader::new(src))?;
+            let src = layer.uncompressed(BufReader::new(src))?;
             let src = threaded::Reader::new(src);
 
             bundles.push(Bundle {
                 unpacker: self,
+                digest: layer.digest().clone(),
                 archive: Archive::new(src),
             })
         }
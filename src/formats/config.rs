@@ -0,0 +1,189 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use std::collections::HashMap;
+
+use serde::de::{Deserializer, IgnoredAny};
+use serde::Deserialize;
+
+use super::Digest;
+
+/// Deserializes a `null` as the default value, as Docker writes `null`s
+fn nullable<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Default + Deserialize<'de>,
+{
+    Ok(Option::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// The parameters for running a container from the image
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    #[allow(dead_code)]
+    #[serde(default, rename = "User")]
+    pub user: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "ExposedPorts", deserialize_with = "nullable")]
+    pub exposed_ports: HashMap<String, IgnoredAny>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "Env", deserialize_with = "nullable")]
+    pub env: Vec<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "Entrypoint")]
+    pub entrypoint: Option<Vec<String>>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "Cmd")]
+    pub cmd: Option<Vec<String>>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "Volumes", deserialize_with = "nullable")]
+    pub volumes: HashMap<String, IgnoredAny>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "WorkingDir")]
+    pub working_dir: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "Labels", deserialize_with = "nullable")]
+    pub labels: HashMap<String, String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "StopSignal")]
+    pub stop_signal: Option<String>,
+}
+
+/// The uncompressed layers that make up the root filesystem
+#[derive(Clone, Debug, Deserialize)]
+pub struct RootFs {
+    #[serde(rename = "type")]
+    pub kind: String,
+
+    #[serde(default, deserialize_with = "nullable")]
+    pub diff_ids: Vec<Digest>,
+}
+
+/// A step in building the image
+#[derive(Clone, Debug, Deserialize)]
+pub struct History {
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub created: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub created_by: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub author: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub comment: Option<String>,
+
+    /// Whether this step left the filesystem unchanged (i.e. had no layer)
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub empty_layer: bool,
+}
+
+/// The configuration of an image
+///
+/// The OCI image configuration was adopted from Docker's, so this parses
+/// both. Only the fields common to the two are kept.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageConfig {
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub created: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub author: Option<String>,
+
+    pub architecture: String,
+
+    pub os: String,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "os.version")]
+    pub os_version: Option<String>,
+
+    #[serde(default)]
+    pub variant: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, deserialize_with = "nullable")]
+    pub config: Config,
+
+    pub rootfs: RootFs,
+
+    #[allow(dead_code)]
+    #[serde(default, deserialize_with = "nullable")]
+    pub history: Vec<History>,
+}
+
+impl ImageConfig {
+    pub const DOCKER: &'static str = "application/vnd.docker.container.image.v1+json";
+    pub const OCI: &'static str = "application/vnd.oci.image.config.v1+json";
+}
+
+#[cfg(test)]
+mod test {
+    use super::ImageConfig;
+
+    #[test]
+    fn parse() {
+        // As written by Docker, with its `null`s
+        let config: ImageConfig = serde_json::from_str(
+            r##"{
+                "architecture": "amd64",
+                "os": "linux",
+                "created": "2021-11-10T18:03:11.104874955Z",
+                "config": {
+                    "Env": ["PATH=/usr/bin"],
+                    "Cmd": ["/bin/sh"],
+                    "Entrypoint": null,
+                    "Labels": null,
+                    "Volumes": null
+                },
+                "container_config": {"Cmd": ["/bin/sh", "-c", "#(nop) CMD"]},
+                "rootfs": {
+                    "type": "layers",
+                    "diff_ids": [
+                        "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+                    ]
+                },
+                "history": [
+                    {"created_by": "ADD rootfs.tar /"},
+                    {"created_by": "CMD [\"/bin/sh\"]", "empty_layer": true}
+                ]
+            }"##,
+        )
+        .unwrap();
+
+        assert_eq!(config.architecture, "amd64");
+        assert_eq!(config.config.env, ["PATH=/usr/bin"]);
+        assert_eq!(config.config.cmd.as_deref(), Some(&["/bin/sh".into()][..]));
+        assert!(config.config.entrypoint.is_none());
+        assert!(config.config.labels.is_empty());
+        assert_eq!(config.rootfs.diff_ids.len(), 1);
+        assert_eq!(config.history.iter().filter(|h| h.empty_layer).count(), 1);
+
+        // A minimal OCI configuration
+        let config: ImageConfig = serde_json::from_str(
+            r#"{"architecture": "arm64", "os": "linux", "variant": "v8",
+                "rootfs": {"type": "layers", "diff_ids": []}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.variant.as_deref(), Some("v8"));
+        assert!(config.config.cmd.is_none());
+        assert!(config.history.is_empty());
+    }
+}
@@ -5,8 +5,8 @@ use std::io::Write;
 use std::str::FromStr;
 
 use anyhow::Result;
-use serde::Deserialize;
 use ring::digest::*;
+use serde::Deserialize;
 
 use crate::iotools::Validatable;
 
@@ -151,6 +151,35 @@ impl FromStr for Digest {
 }
 
 impl Digest {
+    /// Computes the sha256 digest of the given bytes
+    pub fn sha256(data: &[u8]) -> Self {
+        let mut hash = [0; SHA256_OUTPUT_LEN];
+        hash.copy_from_slice(digest(&SHA256, data).as_ref());
+        Self(Inner::Sha256(Context::new(&SHA256), hash))
+    }
+
+    /// Whether the given bytes hash to this digest
+    pub fn verify(&self, data: &[u8]) -> bool {
+        let mut digest = self.clone();
+        digest.write_all(data).unwrap();
+        digest.validate()
+    }
+
+    /// The digest of the data written so far, using the same algorithm
+    pub fn computed(&self) -> Self {
+        fn hash<const N: usize>(context: &Context) -> [u8; N] {
+            let mut hash = [0; N];
+            hash.copy_from_slice(context.clone().finish().as_ref());
+            hash
+        }
+
+        Self(match &self.0 {
+            Inner::Sha256(w, ..) => Inner::Sha256(Context::new(&SHA256), hash(w)),
+            Inner::Sha384(w, ..) => Inner::Sha384(Context::new(&SHA384), hash(w)),
+            Inner::Sha512(w, ..) => Inner::Sha512(Context::new(&SHA512), hash(w)),
+        })
+    }
+
     pub fn algorithm(&self) -> &str {
         match self.0 {
             Inner::Sha256(..) => "sha256",
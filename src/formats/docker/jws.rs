@@ -0,0 +1,123 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+//! The JSON Web Signatures on signed schema 1 manifests (`prettyjws`)
+//!
+//! The signatures are embedded in the manifest they sign. Each protected
+//! header says how to recover the signed payload: keep the first
+//! `formatLength` bytes of the manifest and append `formatTail`.
+
+use anyhow::{anyhow, Context, Result};
+use ring::signature::{self, RsaPublicKeyComponents, UnparsedPublicKey};
+use serde::Deserialize;
+
+/// Decodes unpadded base64url, as used throughout JWS
+fn decode(s: &str) -> Result<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+/// A public key in JWK format
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kty")]
+enum Jwk {
+    #[serde(rename = "EC")]
+    Ec { crv: String, x: String, y: String },
+
+    #[serde(rename = "RSA")]
+    Rsa { n: String, e: String },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Header {
+    #[serde(default)]
+    jwk: Option<Jwk>,
+
+    alg: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct Protected {
+    #[serde(rename = "formatLength")]
+    format_length: usize,
+
+    #[serde(rename = "formatTail")]
+    format_tail: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Signature {
+    header: Header,
+    signature: String,
+    protected: String,
+}
+
+impl Signature {
+    /// Recovers the payload covered by this signature from `manifest`
+    pub fn payload(&self, manifest: &[u8]) -> Result<Vec<u8>> {
+        let protected: Protected = serde_json::from_slice(&decode(&self.protected)?)
+            .context("invalid protected header")?;
+
+        let mut payload = manifest
+            .get(..protected.format_length)
+            .ok_or_else(|| anyhow!("invalid protected header: formatLength out of range"))?
+            .to_vec();
+        payload.extend(decode(&protected.format_tail)?);
+
+        Ok(payload)
+    }
+
+    /// Verifies this signature on `manifest` and returns the signed payload
+    ///
+    /// Only keys embedded as a JWK are supported. The key is not checked
+    /// against anything: as with Docker, this proves that the manifest is
+    /// intact, not who signed it.
+    pub fn verify(&self, manifest: &[u8]) -> Result<Vec<u8>> {
+        let payload = self.payload(manifest)?;
+        let encoded = base64::encode_config(&payload, base64::URL_SAFE_NO_PAD);
+        let input = format!("{}.{}", self.protected, encoded);
+        let signature = decode(&self.signature)?;
+
+        let jwk = self
+            .header
+            .jwk
+            .as_ref()
+            .ok_or_else(|| anyhow!("unsupported signature: no jwk"))?;
+
+        let verified = match (jwk, &*self.header.alg) {
+            (Jwk::Ec { crv, x, y }, alg) => {
+                let algorithm = match (&**crv, alg) {
+                    ("P-256", "ES256") => &signature::ECDSA_P256_SHA256_FIXED,
+                    ("P-384", "ES384") => &signature::ECDSA_P384_SHA384_FIXED,
+                    _ => return Err(anyhow!("unsupported signature: {} with {}", alg, crv)),
+                };
+
+                // An uncompressed point
+                let mut point = vec![4];
+                point.extend(decode(x)?);
+                point.extend(decode(y)?);
+
+                UnparsedPublicKey::new(algorithm, point).verify(input.as_bytes(), &signature)
+            }
+
+            (Jwk::Rsa { n, e }, alg) => {
+                let algorithm = match alg {
+                    "RS256" => &signature::RSA_PKCS1_2048_8192_SHA256,
+                    "RS384" => &signature::RSA_PKCS1_2048_8192_SHA384,
+                    "RS512" => &signature::RSA_PKCS1_2048_8192_SHA512,
+                    _ => return Err(anyhow!("unsupported signature: {} with RSA", alg)),
+                };
+
+                let key = RsaPublicKeyComponents {
+                    n: decode(n)?,
+                    e: decode(e)?,
+                };
+
+                key.verify(algorithm, input.as_bytes(), &signature)
+            }
+        };
+
+        verified.map_err(|_| anyhow!("invalid signature"))?;
+        Ok(payload)
+    }
+}
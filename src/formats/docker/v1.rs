@@ -1,12 +1,32 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+use std::collections::HashSet;
+
+use anyhow::{anyhow, Context, Result};
 use serde::Deserialize;
 
+use super::jws::Signature;
 use crate::formats::Digest;
 
 #[derive(Clone, Debug, Deserialize)]
-pub struct History {}
+pub struct History {
+    #[serde(rename = "v1Compatibility")]
+    pub v1_compatibility: String,
+}
+
+/// The parts of a `v1Compatibility` entry that we use
+#[derive(Clone, Debug, Deserialize)]
+struct Compatibility {
+    id: String,
+
+    #[serde(default)]
+    parent: Option<String>,
+
+    /// Whether the layer is empty (i.e. from a metadata-only instruction)
+    #[serde(default)]
+    throwaway: bool,
+}
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Layer {
@@ -30,4 +50,220 @@ pub struct Manifest {
 
     #[serde(default)]
     pub history: Vec<History>,
+
+    #[serde(default)]
+    pub signatures: Vec<Signature>,
+}
+
+impl Manifest {
+    /// The layers to apply, oldest first
+    ///
+    /// Schema 1 lists the layers newest first, each with a history entry
+    /// naming the layer and its parent. As Docker does, adjacent repeats of a
+    /// layer are dropped and the chain of parents must be unbroken. Throwaway
+    /// layers are empty, so they are skipped.
+    pub fn diffs(&self) -> Result<Vec<&Layer>> {
+        if self.layers.len() != self.history.len() {
+            return Err(anyhow!(
+                "{} fsLayers but {} history entries",
+                self.layers.len(),
+                self.history.len()
+            ));
+        }
+
+        let compat = self
+            .history
+            .iter()
+            .map(|h| serde_json::from_str(&h.v1_compatibility))
+            .collect::<Result<Vec<Compatibility>, _>>()
+            .context("invalid v1Compatibility")?;
+
+        for c in compat.iter() {
+            if c.id.len() != 64 || !c.id.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(anyhow!("invalid layer id: {:?}", c.id));
+            }
+        }
+
+        // A repeated layer must follow itself directly.
+        let mut seen = HashSet::new();
+        for (i, c) in compat.iter().enumerate() {
+            let repeat = i > 0 && compat[i - 1].id == c.id;
+            if !seen.insert(&c.id) && !repeat {
+                return Err(anyhow!("layer {} appears more than once", c.id));
+            }
+        }
+
+        let mut diffs = Vec::new();
+        for (i, c) in compat.iter().enumerate().rev() {
+            let parent = compat.get(i + 1).map(|p| &p.id);
+            if parent == Some(&c.id) {
+                continue;
+            }
+
+            if c.parent.as_ref().filter(|p| !p.is_empty()) != parent {
+                return Err(anyhow!("layer {} has an invalid parent", c.id));
+            }
+
+            if !c.throwaway {
+                diffs.push(&self.layers[i]);
+            }
+        }
+
+        Ok(diffs)
+    }
+
+    /// Verifies the signatures on `bytes`, which is this manifest
+    ///
+    /// Returns the signed payload, which is the manifest without its
+    /// signatures. This is what the manifest's digest covers.
+    pub fn payload(&self, bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut payload = None;
+
+        for signature in self.signatures.iter() {
+            let signed = signature.verify(bytes)?;
+            match &payload {
+                Some(payload) if *payload != signed => {
+                    return Err(anyhow!("signatures cover different payloads"))
+                }
+                _ => payload = Some(signed),
+            }
+        }
+
+        payload.ok_or_else(|| anyhow!("manifest is not signed"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Manifest;
+
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+
+    fn id(n: u8) -> String {
+        format!("{:064x}", n)
+    }
+
+    fn blob(n: u8) -> String {
+        format!("sha256:{:064x}", n)
+    }
+
+    /// A manifest with (id, parent, throwaway, blob) entries, newest first
+    fn manifest(entries: &[(u8, Option<u8>, bool, u8)]) -> String {
+        let layers: Vec<_> = entries
+            .iter()
+            .map(|e| format!(r#"{{"blobSum": "{}"}}"#, blob(e.3)))
+            .collect();
+
+        let history: Vec<_> = entries
+            .iter()
+            .map(|(i, p, t, ..)| {
+                let mut compat = format!(r#"{{"id": "{}""#, id(*i));
+                if let Some(p) = p {
+                    compat += &format!(r#", "parent": "{}""#, id(*p));
+                }
+                if *t {
+                    compat += r#", "throwaway": true"#;
+                }
+                compat += "}";
+                serde_json::json!({ "v1Compatibility": compat }).to_string()
+            })
+            .collect();
+
+        format!(
+            "{{\n   \"schemaVersion\": 1,\n   \"name\": \"boot\",\n   \"tag\": \"latest\",\n   \"architecture\": \"amd64\",\n   \"fsLayers\": [{}],\n   \"history\": [{}]\n}}",
+            layers.join(", "),
+            history.join(", ")
+        )
+    }
+
+    #[test]
+    fn diffs() {
+        // Newest first: a throwaway layer, a repeated layer and the base
+        let json = manifest(&[
+            (3, Some(2), true, 9),
+            (2, Some(1), false, 2),
+            (2, Some(1), false, 2),
+            (1, None, false, 1),
+        ]);
+        let m: Manifest = serde_json::from_str(&json).unwrap();
+        let diffs: Vec<_> = m
+            .diffs()
+            .unwrap()
+            .iter()
+            .map(|l| l.digest.to_string())
+            .collect();
+        assert_eq!(diffs, [blob(1), blob(2)]);
+
+        let json = manifest(&[(2, Some(3), false, 2), (1, None, false, 1)]);
+        let m: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(m.diffs().is_err());
+
+        let json = manifest(&[
+            (1, Some(2), false, 1),
+            (2, Some(1), false, 2),
+            (1, None, false, 1),
+        ]);
+        let m: Manifest = serde_json::from_str(&json).unwrap();
+        assert!(m.diffs().is_err());
+    }
+
+    #[test]
+    fn payload() {
+        let payload = manifest(&[(1, None, false, 1)]);
+
+        // Sign as libtrust does: the signatures go before the closing brace.
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let point = key.public_key().as_ref();
+
+        let b64 = |b: &[u8]| base64::encode_config(b, base64::URL_SAFE_NO_PAD);
+        let length = payload.rfind("\n}").unwrap();
+        let protected = b64(format!(
+            r#"{{"formatLength":{},"formatTail":"{}","time":"2021-11-10T18:03:11Z"}}"#,
+            length,
+            b64(b"\n}")
+        )
+        .as_bytes());
+
+        let input = format!("{}.{}", protected, b64(payload.as_bytes()));
+        let signature = key.sign(&rng, input.as_bytes()).unwrap();
+
+        let sign = |signature: &[u8]| {
+            format!(
+                "{},\n   \"signatures\": [{}]\n}}",
+                &payload[..length],
+                serde_json::json!({
+                    "header": {
+                        "jwk": {"kty": "EC", "crv": "P-256", "x": b64(&point[1..33]), "y": b64(&point[33..])},
+                        "alg": "ES256"
+                    },
+                    "signature": b64(signature),
+                    "protected": protected,
+                })
+            )
+        };
+
+        let signed = sign(signature.as_ref());
+        let m: Manifest = serde_json::from_str(&signed).unwrap();
+        assert_eq!(m.payload(signed.as_bytes()).unwrap(), payload.as_bytes());
+
+        // A tampered manifest fails to verify.
+        let tampered = signed.replace("amd64", "arm64");
+        let m: Manifest = serde_json::from_str(&tampered).unwrap();
+        assert!(m.payload(tampered.as_bytes()).is_err());
+
+        // As does a bad signature.
+        let mut forged = signature.as_ref().to_vec();
+        forged[0] ^= 1;
+        let forged = sign(&forged);
+        let m: Manifest = serde_json::from_str(&forged).unwrap();
+        assert!(m.payload(forged.as_bytes()).is_err());
+
+        // Unsigned manifests have no payload.
+        let m: Manifest = serde_json::from_str(&payload).unwrap();
+        assert!(m.payload(payload.as_bytes()).is_err());
+    }
 }
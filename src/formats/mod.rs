@@ -1,27 +1,186 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
+mod config;
 mod digest;
 pub mod docker;
 pub mod oci;
+mod reference;
 
+pub use self::config::ImageConfig;
 pub use self::digest::Digest;
+pub use self::reference::Reference;
 
+use anyhow::{anyhow, Result};
+use serde::de::IgnoredAny;
 use serde::Deserialize;
 
-#[derive(Clone, Debug, Deserialize)]
-#[serde(untagged)]
+#[derive(Clone, Debug)]
 pub enum Manifest {
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v1+json")]
-    #[serde(alias = "application/vnd.docker.distribution.manifest.v1+prettyjws")]
     DockerV1(docker::v1::Manifest),
-
-    #[serde(rename = "application/vnd.docker.distribution.manifest.v2+json")]
     DockerV2(docker::v2::Manifest),
-
-    #[serde(rename = "application/vnd.docker.distribution.manifest.list.v2+json")]
     DockerV2List(docker::v2::ManifestList),
-
-    #[serde(rename = "application/vnd.oci.image.manifest.v1+json")]
     Oci(oci::Manifest),
+    OciIndex(oci::Index),
+}
+
+impl Manifest {
+    pub const DOCKER_V1: &'static str = "application/vnd.docker.distribution.manifest.v1+json";
+    pub const DOCKER_V1_SIGNED: &'static str =
+        "application/vnd.docker.distribution.manifest.v1+prettyjws";
+    pub const DOCKER_V2: &'static str = "application/vnd.docker.distribution.manifest.v2+json";
+    pub const DOCKER_V2_LIST: &'static str =
+        "application/vnd.docker.distribution.manifest.list.v2+json";
+    pub const OCI: &'static str = "application/vnd.oci.image.manifest.v1+json";
+    pub const OCI_INDEX: &'static str = "application/vnd.oci.image.index.v1+json";
+
+    /// The media types we accept, in order of preference
+    pub const MEDIA_TYPES: &'static [&'static str] = &[
+        Self::OCI_INDEX,
+        Self::DOCKER_V2_LIST,
+        Self::OCI,
+        Self::DOCKER_V2,
+        Self::DOCKER_V1_SIGNED,
+        Self::DOCKER_V1,
+    ];
+
+    /// Content types that say nothing about the kind of manifest
+    const GENERIC: &'static [&'static str] = &["application/json", "text/plain"];
+
+    /// Parses a manifest of the given content type
+    ///
+    /// If the content type is missing or generic, the type is taken from the
+    /// `mediaType` field of the manifest itself. Failing that, it is inferred
+    /// from the schema version and the fields present.
+    pub fn parse(content_type: Option<&str>, bytes: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Probe {
+            #[serde(default, rename = "schemaVersion")]
+            schema_version: Option<usize>,
+
+            #[serde(default, rename = "mediaType")]
+            media_type: Option<String>,
+
+            #[serde(default)]
+            manifests: Option<IgnoredAny>,
+        }
+
+        let content_type = content_type
+            .and_then(|ct| ct.split(';').next())
+            .map(str::trim)
+            .filter(|ct| !ct.is_empty() && !Self::GENERIC.contains(ct));
+
+        let media_type = match content_type {
+            Some(ct) => ct.to_string(),
+            None => {
+                let probe: Probe = serde_json::from_slice(bytes)?;
+                match (probe.media_type, probe.schema_version, probe.manifests) {
+                    (Some(mt), ..) => mt,
+                    (None, Some(1), ..) => Self::DOCKER_V1.into(),
+                    (None, Some(2), Some(..)) => Self::OCI_INDEX.into(),
+                    (None, Some(2), None) => Self::OCI.into(),
+                    (None, ..) => return Err(anyhow!("unable to determine the manifest type")),
+                }
+            }
+        };
+
+        Ok(match &*media_type {
+            Self::DOCKER_V1 => Self::DockerV1(serde_json::from_slice(bytes)?),
+            Self::DOCKER_V1_SIGNED => {
+                let manifest: docker::v1::Manifest = serde_json::from_slice(bytes)?;
+                if manifest.signatures.is_empty() {
+                    return Err(anyhow!("signed manifest has no signatures"));
+                }
+
+                Self::DockerV1(manifest)
+            }
+            Self::DOCKER_V2 => Self::DockerV2(serde_json::from_slice(bytes)?),
+            Self::DOCKER_V2_LIST => Self::DockerV2List(serde_json::from_slice(bytes)?),
+            Self::OCI => Self::Oci(serde_json::from_slice(bytes)?),
+            Self::OCI_INDEX => Self::OciIndex(serde_json::from_slice(bytes)?),
+            other => return Err(anyhow!("unsupported manifest type: {}", other)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Manifest;
+
+    const DOCKER_V2: &str = r#"{
+        "schemaVersion": 2,
+        "mediaType": "application/vnd.docker.distribution.manifest.v2+json",
+        "config": {
+            "mediaType": "application/vnd.docker.container.image.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        },
+        "layers": []
+    }"#;
+
+    const OCI: &str = r#"{
+        "schemaVersion": 2,
+        "config": {
+            "mediaType": "application/vnd.oci.image.config.v1+json",
+            "size": 2,
+            "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+        },
+        "layers": []
+    }"#;
+
+    #[test]
+    fn parse() {
+        let ct = "application/vnd.docker.distribution.manifest.v2+json; charset=utf-8";
+        let manifest = Manifest::parse(Some(ct), DOCKER_V2.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV2(..)));
+
+        let manifest = Manifest::parse(Some("application/json"), DOCKER_V2.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::DockerV2(..)));
+
+        let manifest = Manifest::parse(None, OCI.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::Oci(..)));
+
+        let ct = "application/vnd.oci.image.manifest.v1+json";
+        let manifest = Manifest::parse(Some(ct), OCI.as_bytes()).unwrap();
+        assert!(matches!(manifest, Manifest::Oci(..)));
+
+        let ct = "application/vnd.oci.artifact.manifest.v1+json";
+        assert!(Manifest::parse(Some(ct), OCI.as_bytes()).is_err());
+        assert!(Manifest::parse(None, b"{}").is_err());
+    }
+
+    #[test]
+    fn index() {
+        const INDEX: &str = r#"{
+            "schemaVersion": 2,
+            "mediaType": "application/vnd.oci.image.index.v1+json",
+            "artifactType": "application/vnd.example.boot",
+            "manifests": [{
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                "platform": {"architecture": "arm64", "os": "linux", "variant": "v8"}
+            }, {
+                "mediaType": "application/vnd.oci.image.index.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            }],
+            "subject": {
+                "mediaType": "application/vnd.oci.image.manifest.v1+json",
+                "size": 2,
+                "digest": "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"
+            },
+            "annotations": {"org.opencontainers.image.ref.name": "latest"}
+        }"#;
+
+        let index = match Manifest::parse(None, INDEX.as_bytes()).unwrap() {
+            Manifest::OciIndex(index) => index,
+            other => panic!("unexpected manifest: {:?}", other),
+        };
+
+        assert_eq!(index.manifests.len(), 2);
+        assert_eq!(index.manifests[0].platform.as_ref().unwrap().os, "linux");
+        assert!(index.manifests[1].platform.is_none());
+        assert!(index.subject.is_some());
+    }
 }
@@ -7,6 +7,23 @@ use serde::Deserialize;
 
 use super::Digest;
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct Platform {
+    pub architecture: String,
+
+    pub os: String,
+
+    #[allow(dead_code)]
+    #[serde(rename = "os.version")]
+    pub os_version: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "os.features")]
+    pub os_features: Vec<String>,
+
+    pub variant: Option<String>,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Descriptor {
     #[serde(rename = "mediaType")]
@@ -21,13 +38,22 @@ pub struct Descriptor {
 
     #[serde(default)]
     pub annotations: HashMap<String, String>,
+
+    #[serde(default)]
+    pub platform: Option<Platform>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "artifactType")]
+    pub artifact_type: Option<String>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Manifest {
+    #[allow(dead_code)]
     #[serde(rename = "schemaVersion")]
     pub schema_version: usize,
 
+    #[allow(dead_code)]
     #[serde(rename = "mediaType")]
     pub media_type: Option<String>,
 
@@ -35,6 +61,32 @@ pub struct Manifest {
 
     pub layers: Vec<Descriptor>,
 
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Index {
+    #[allow(dead_code)]
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: usize,
+
+    #[allow(dead_code)]
+    #[serde(rename = "mediaType")]
+    pub media_type: Option<String>,
+
+    #[allow(dead_code)]
+    #[serde(default, rename = "artifactType")]
+    pub artifact_type: Option<String>,
+
+    pub manifests: Vec<Descriptor>,
+
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub subject: Option<Descriptor>,
+
+    #[allow(dead_code)]
     #[serde(default)]
     pub annotations: HashMap<String, String>,
 }
@@ -0,0 +1,317 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright (C) 2021 Profian, Inc.
+
+use super::Digest;
+
+use std::fmt::Display;
+use std::net::Ipv6Addr;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Invalid {
+    Empty,
+    Registry(String),
+    Repository(String),
+    Tag(String),
+    Digest(String),
+    TooLong,
+}
+
+impl std::error::Error for Invalid {}
+impl Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Invalid::Empty => f.write_str("empty image reference"),
+            Invalid::Registry(s) => write!(f, "invalid registry: {:?}", s),
+            Invalid::Repository(s) => write!(f, "invalid repository name: {:?}", s),
+            Invalid::Tag(s) => write!(f, "invalid tag: {:?}", s),
+            Invalid::Digest(s) => write!(f, "invalid digest: {:?}", s),
+            Invalid::TooLong => write!(f, "repository name longer than {} bytes", MAX_NAME),
+        }
+    }
+}
+
+/// The maximum length of `registry/repository`
+const MAX_NAME: usize = 255;
+
+/// The maximum length of a tag
+const MAX_TAG: usize = 128;
+
+fn alnum(b: u8) -> bool {
+    b.is_ascii_lowercase() || b.is_ascii_digit()
+}
+
+/// Validates a path component: `[a-z0-9]+(?:(?:[._]|__|[-]+)[a-z0-9]+)*`
+fn component(s: &str) -> bool {
+    let bytes = s.as_bytes();
+    match (bytes.first(), bytes.last()) {
+        (Some(first), Some(last)) if alnum(*first) && alnum(*last) => (),
+        _ => return false,
+    }
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        while i < bytes.len() && !alnum(bytes[i]) {
+            i += 1;
+        }
+
+        match &s[start..i] {
+            "" | "." | "_" | "__" => (),
+            sep if sep.bytes().all(|b| b == b'-') => (),
+            _ => return false,
+        }
+
+        while i < bytes.len() && alnum(bytes[i]) {
+            i += 1;
+        }
+    }
+
+    true
+}
+
+/// Validates a registry: a domain name, IPv4 or `[IPv6]` host and a port
+fn registry(s: &str) -> bool {
+    let (host, port) = match s.strip_prefix('[') {
+        Some(rest) => match rest.split_once(']') {
+            Some((ip, "")) => return ip.parse::<Ipv6Addr>().is_ok(),
+            Some((ip, rest)) => match (ip.parse::<Ipv6Addr>(), rest.strip_prefix(':')) {
+                (Ok(..), Some(port)) => (None, Some(port)),
+                _ => return false,
+            },
+            None => return false,
+        },
+
+        None => match s.rsplit_once(':') {
+            Some((host, port)) => (Some(host), Some(port)),
+            None => (Some(s), None),
+        },
+    };
+
+    let host = host.is_none_or(|host| {
+        host.split('.').all(|c| {
+            let bytes = c.as_bytes();
+            match (bytes.first(), bytes.last()) {
+                (Some(first), Some(last)) => {
+                    first.is_ascii_alphanumeric()
+                        && last.is_ascii_alphanumeric()
+                        && bytes
+                            .iter()
+                            .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+                }
+                _ => false,
+            }
+        })
+    });
+
+    let port = port.is_none_or(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()));
+    host && port
+}
+
+/// Validates a tag: `[\w][\w.-]{0,127}`
+fn tag(s: &str) -> bool {
+    let word = |b: u8| b.is_ascii_alphanumeric() || b == b'_';
+
+    match s.as_bytes() {
+        [first, rest @ ..] if word(*first) && s.len() <= MAX_TAG => {
+            rest.iter().all(|b| word(*b) || *b == b'.' || *b == b'-')
+        }
+        _ => false,
+    }
+}
+
+/// An image reference: `[registry/]repository[:tag][@digest]`
+///
+/// This follows the grammar of the distribution project. As with Docker, the
+/// first component of the name is the registry if it contains a `.` or a
+/// `:`, is `localhost`, is an IPv6 literal or contains uppercase letters.
+/// Otherwise, the reference is a short name without a registry.
+#[derive(Clone, Debug)]
+pub struct Reference {
+    pub registry: Option<String>,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<Digest>,
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(registry) = &self.registry {
+            write!(f, "{}/", registry)?;
+        }
+
+        f.write_str(&self.repository)?;
+
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Reference {
+    type Err = Invalid;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(Invalid::Empty);
+        }
+
+        // Split off the digest.
+        let (rest, digest) = match s.split_once('@') {
+            Some((rest, digest)) => match digest.parse() {
+                Ok(parsed) if digest.contains(':') => (rest, Some(parsed)),
+                _ => return Err(Invalid::Digest(digest.into())),
+            },
+            None => (s, None),
+        };
+
+        // The tag follows the last `:` in the last component.
+        let last = rest.rfind('/').map(|n| n + 1).unwrap_or_default();
+        let (name, tag) = match rest[last..].rfind(':') {
+            Some(n) => (&rest[..last + n], Some(&rest[last + n + 1..])),
+            None => (rest, None),
+        };
+
+        if let Some(t) = tag.filter(|t| !self::tag(t)) {
+            return Err(Invalid::Tag(t.into()));
+        }
+
+        // Split off the registry.
+        let (registry, repository) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains(['.', ':', '['])
+                    || first == "localhost"
+                    || first.bytes().any(|b| b.is_ascii_uppercase()) =>
+            {
+                (Some(first), rest)
+            }
+
+            _ => (None, name),
+        };
+
+        if let Some(r) = registry.filter(|r| !self::registry(r)) {
+            return Err(Invalid::Registry(r.into()));
+        }
+
+        if repository.is_empty() || !repository.split('/').all(component) {
+            return Err(Invalid::Repository(repository.into()));
+        }
+
+        if name.len() > MAX_NAME {
+            return Err(Invalid::TooLong);
+        }
+
+        Ok(Self {
+            registry: registry.map(String::from),
+            repository: repository.into(),
+            tag: tag.map(String::from),
+            digest,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Invalid, Reference};
+
+    const DIGEST: &str = "sha256:44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a";
+
+    #[test]
+    fn parse() {
+        let valid = [
+            ("fedora", None, "fedora", None, false),
+            ("fedora:35", None, "fedora", Some("35"), false),
+            ("library/fedora", None, "library/fedora", None, false),
+            (
+                "quay.io/fedora/fedora:35",
+                Some("quay.io"),
+                "fedora/fedora",
+                Some("35"),
+                false,
+            ),
+            ("localhost/boot", Some("localhost"), "boot", None, false),
+            (
+                "localhost:5000/boot:v1.0-rc_1",
+                Some("localhost:5000"),
+                "boot",
+                Some("v1.0-rc_1"),
+                false,
+            ),
+            ("[::1]:5000/foo", Some("[::1]:5000"), "foo", None, false),
+            (
+                "[fe80::1]/foo:bar",
+                Some("[fe80::1]"),
+                "foo",
+                Some("bar"),
+                false,
+            ),
+            ("Registry/foo", Some("Registry"), "foo", None, false),
+            (
+                "10.0.0.1:5000/a__b/c--d/e.f",
+                Some("10.0.0.1:5000"),
+                "a__b/c--d/e.f",
+                None,
+                false,
+            ),
+            (
+                "docker.io/library/fedora:35@DIGEST",
+                Some("docker.io"),
+                "library/fedora",
+                Some("35"),
+                true,
+            ),
+            ("fedora@DIGEST", None, "fedora", None, true),
+        ];
+
+        for (input, registry, repository, tag, digest) in valid {
+            let input = input.replace("DIGEST", DIGEST);
+            let reference: Reference = input.parse().unwrap();
+            assert_eq!(reference.registry.as_deref(), registry, "{}", input);
+            assert_eq!(reference.repository, repository, "{}", input);
+            assert_eq!(reference.tag.as_deref(), tag, "{}", input);
+            assert_eq!(reference.digest.is_some(), digest, "{}", input);
+            assert_eq!(reference.to_string(), input);
+        }
+
+        let invalid = [
+            ("", Invalid::Empty),
+            ("Fedora", Invalid::Repository("Fedora".into())),
+            ("quay.io/Fedora", Invalid::Repository("Fedora".into())),
+            ("quay.io/", Invalid::Repository("".into())),
+            ("quay.io//foo", Invalid::Repository("/foo".into())),
+            ("foo/-bar", Invalid::Repository("foo/-bar".into())),
+            ("foo/bar..baz", Invalid::Repository("foo/bar..baz".into())),
+            ("foo:-bar", Invalid::Tag("-bar".into())),
+            ("foo:", Invalid::Tag("".into())),
+            ("foo@sha256:abc", Invalid::Digest("sha256:abc".into())),
+            (
+                "foo@44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a",
+                Invalid::Digest(
+                    "44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a".into(),
+                ),
+            ),
+            ("[::1/foo", Invalid::Registry("[::1".into())),
+            ("[::1]abc/foo", Invalid::Registry("[::1]abc".into())),
+            ("[::1]:/foo", Invalid::Registry("[::1]:".into())),
+            ("[zz::1]:5000/foo", Invalid::Registry("[zz::1]:5000".into())),
+            ("host.-io/foo", Invalid::Registry("host.-io".into())),
+            (
+                "localhost:50a0/foo",
+                Invalid::Registry("localhost:50a0".into()),
+            ),
+        ];
+
+        for (input, err) in invalid {
+            assert_eq!(input.parse::<Reference>().unwrap_err(), err, "{}", input);
+        }
+
+        let long = format!("quay.io/{}", "a".repeat(250));
+        assert_eq!(long.parse::<Reference>().unwrap_err(), Invalid::TooLong);
+    }
+}
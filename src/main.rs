@@ -1,8 +1,6 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright (C) 2021 Profian, Inc.
 
-#![feature(asm)]
-
 mod api;
 mod commands;
 mod formats;
@@ -12,5 +10,10 @@ use commands::Command;
 use structopt::StructOpt;
 
 fn main() -> anyhow::Result<()> {
+    // Messages go to stderr at `info` and above, unless `RUST_LOG` says otherwise.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"))
+        .format_target(false)
+        .init();
+
     commands::Main::from_args().execute()
 }